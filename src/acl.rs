@@ -0,0 +1,132 @@
+use std::{fmt, net::IpAddr, str::FromStr};
+
+/// An address range in CIDR notation; a bare address is a single-host range.
+#[derive(Clone, Debug)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_matches(
+                u32::from(net).into(),
+                u32::from(ip).into(),
+                self.prefix_len,
+                32,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(net), u128::from(ip), self.prefix_len, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(range: &str) -> Result<IpNet, String> {
+        let (addr, prefix_len) = match range.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (range, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("invalid address `{}`", addr))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(|| format!("invalid prefix length `{}`", prefix_len))?,
+            None => max_len,
+        };
+        Ok(IpNet {
+            addr: canonical(addr),
+            prefix_len,
+        })
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Deny rules win; with an allowlist configured anything not on it is refused.
+pub fn is_allowed(ip: IpAddr, allow: &[IpNet], deny: &[IpNet]) -> bool {
+    if deny.iter().any(|net| net.contains(ip)) {
+        return false;
+    }
+    allow.is_empty() || allow.iter().any(|net| net.contains(ip))
+}
+
+fn prefix_matches(net: u128, ip: u128, prefix_len: u8, bits: u8) -> bool {
+    let shift = u32::from(bits - prefix_len);
+    if shift >= u128::BITS {
+        return true;
+    }
+    (net >> shift) == (ip >> shift)
+}
+
+/// Dual-stack listeners hand us v4 peers as `::ffff:a.b.c.d`, compare those as v4.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn net(range: &str) -> IpNet {
+        range.parse().unwrap()
+    }
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn ranges_parse_and_display_in_cidr() {
+        assert_eq!(net("10.0.0.0/8").to_string(), "10.0.0.0/8");
+        assert_eq!(net("192.168.1.7").to_string(), "192.168.1.7/32");
+        assert_eq!(net("::1").to_string(), "::1/128");
+        assert_eq!(net("::ffff:10.1.2.3/32").to_string(), "10.1.2.3/32");
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("10.0.0/8".parse::<IpNet>().is_err());
+        assert!("10.0.0.0/x".parse::<IpNet>().is_err());
+    }
+
+    #[test]
+    fn prefixes_match_the_leading_bits() {
+        assert!(net("10.0.0.0/8").contains(ip("10.255.1.2")));
+        assert!(!net("10.0.0.0/8").contains(ip("11.0.0.1")));
+        assert!(net("192.168.1.0/23").contains(ip("192.168.0.9")));
+        assert!(!net("192.168.1.0/24").contains(ip("192.168.0.9")));
+        assert!(net("0.0.0.0/0").contains(ip("203.0.113.9")));
+        assert!(net("2001:db8::/32").contains(ip("2001:db8:1::1")));
+        assert!(!net("2001:db8::/32").contains(ip("2001:db9::1")));
+        assert!(net("::/0").contains(ip("::1")));
+        // v4 and v6 ranges never overlap, except through the mapped form
+        assert!(!net("0.0.0.0/0").contains(ip("::1")));
+        assert!(net("127.0.0.0/8").contains(ip("::ffff:127.0.0.1")));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let allow = [net("10.0.0.0/8")];
+        let deny = [net("10.0.0.13")];
+        assert!(is_allowed(ip("10.0.0.1"), &allow, &deny));
+        assert!(!is_allowed(ip("10.0.0.13"), &allow, &deny));
+        assert!(!is_allowed(ip("192.0.2.1"), &allow, &deny));
+        assert!(is_allowed(ip("192.0.2.1"), &[], &deny));
+        assert!(is_allowed(ip("192.0.2.1"), &[], &[]));
+    }
+}
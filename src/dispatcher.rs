@@ -0,0 +1,336 @@
+use std::{
+    collections::VecDeque,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+pub type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Decides which thread ends up running an accepted connection. Shared by
+/// every listener's accept loop, hence `Sync`.
+pub trait Dispatcher: Sync {
+    fn dispatch(&self, job: Job);
+}
+
+/// Spawns a fresh thread for every connection.
+#[derive(Default)]
+pub struct ThreadPerConnection {
+    next: AtomicUsize,
+}
+
+impl Dispatcher for ThreadPerConnection {
+    fn dispatch(&self, job: Job) {
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        spawn_named(format!("http-conn-{}", id), job);
+    }
+}
+
+/// Spawns a thread with a name that shows up in debuggers, profilers and
+/// panic messages, returning whether it actually started.
+fn spawn_named(name: String, f: impl FnOnce() + Send + 'static) -> bool {
+    match thread::Builder::new().name(name).spawn(f) {
+        Ok(_) => true,
+        Err(e) => {
+            eprintln!("error: could not spawn thread: {}", e);
+            false
+        }
+    }
+}
+
+/// Runs a job on a long-lived worker. A panicking connection has already
+/// reported itself through the panic hook; it mustn't take the worker with it.
+fn run_job(job: Job) {
+    let _panicked = panic::catch_unwind(AssertUnwindSafe(job));
+}
+
+/// Workers pulling connections off one shared queue. The pool starts at
+/// `min` threads, spawns more (up to `max`) whenever jobs are queued with no
+/// idle worker to take them, and lets extras exit after sitting idle.
+pub struct ThreadPool {
+    shared: Arc<PoolShared>,
+}
+
+struct PoolShared {
+    state: Mutex<PoolState>,
+    available: Condvar,
+    min: usize,
+    max: usize,
+    idle_timeout: Duration,
+    next_id: AtomicUsize,
+}
+
+struct PoolState {
+    queue: VecDeque<Job>,
+    workers: usize,
+    idle: usize,
+}
+
+/// How long a worker above the minimum waits for work before exiting.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+impl ThreadPool {
+    pub fn new(min: usize, max: usize) -> ThreadPool {
+        ThreadPool::with_idle_timeout(min, max, IDLE_TIMEOUT)
+    }
+
+    fn with_idle_timeout(min: usize, max: usize, idle_timeout: Duration) -> ThreadPool {
+        let min = min.max(1);
+        let shared = Arc::new(PoolShared {
+            state: Mutex::new(PoolState {
+                queue: VecDeque::new(),
+                workers: min,
+                idle: 0,
+            }),
+            available: Condvar::new(),
+            min,
+            max: max.max(min),
+            idle_timeout,
+            next_id: AtomicUsize::new(0),
+        });
+
+        for _ in 0..min {
+            if !spawn_pool_worker(Arc::clone(&shared)) {
+                if let Ok(mut state) = shared.state.lock() {
+                    state.workers -= 1;
+                }
+            }
+        }
+
+        ThreadPool { shared }
+    }
+}
+
+fn spawn_pool_worker(shared: Arc<PoolShared>) -> bool {
+    let id = shared.next_id.fetch_add(1, Ordering::Relaxed);
+    spawn_named(format!("http-worker-{}", id), move || {
+        let mut state = match shared.state.lock() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+        loop {
+            if let Some(job) = state.queue.pop_front() {
+                drop(state);
+                run_job(job);
+                state = match shared.state.lock() {
+                    Ok(state) => state,
+                    Err(_) => return,
+                };
+                continue;
+            }
+
+            state.idle += 1;
+            let (guard, timeout) = match shared.available.wait_timeout(state, shared.idle_timeout) {
+                Ok(woken) => woken,
+                Err(_) => return,
+            };
+            state = guard;
+            state.idle -= 1;
+
+            if timeout.timed_out() && state.queue.is_empty() && state.workers > shared.min {
+                state.workers -= 1;
+                return;
+            }
+        }
+    })
+}
+
+impl Dispatcher for ThreadPool {
+    fn dispatch(&self, job: Job) {
+        let shared = &self.shared;
+        let mut state = match shared.state.lock() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+        state.queue.push_back(job);
+
+        // Grow when the backlog outnumbers the workers waiting to pick it up
+        if state.queue.len() > state.idle && state.workers < shared.max {
+            state.workers += 1;
+            if !spawn_pool_worker(Arc::clone(shared)) {
+                state.workers -= 1;
+            }
+        }
+        shared.available.notify_one();
+    }
+}
+
+/// Every worker owns a queue; idle workers steal from the back of the others'.
+pub struct WorkStealing {
+    shared: Arc<StealingQueues>,
+    next: AtomicUsize,
+}
+
+struct StealingQueues {
+    queues: Vec<Mutex<VecDeque<Job>>>,
+    pending: AtomicUsize,
+    sleep: Mutex<()>,
+    wakeup: Condvar,
+}
+
+impl StealingQueues {
+    fn find_job(&self, worker: usize) -> Option<Job> {
+        let count = self.queues.len();
+        let own = self.queues[worker].lock().ok()?.pop_front();
+        let job = own.or_else(|| {
+            (1..count).find_map(|offset| {
+                let victim = (worker + offset) % count;
+                self.queues[victim].lock().ok()?.pop_back()
+            })
+        })?;
+        self.pending.fetch_sub(1, Ordering::SeqCst);
+        Some(job)
+    }
+}
+
+impl WorkStealing {
+    pub fn new(size: usize) -> WorkStealing {
+        let size = size.max(1);
+        let shared = Arc::new(StealingQueues {
+            queues: (0..size).map(|_| Mutex::new(VecDeque::new())).collect(),
+            pending: AtomicUsize::new(0),
+            sleep: Mutex::new(()),
+            wakeup: Condvar::new(),
+        });
+
+        for worker in 0..size {
+            let shared = Arc::clone(&shared);
+            spawn_named(format!("http-worker-{}", worker), move || loop {
+                if let Some(job) = shared.find_job(worker) {
+                    run_job(job);
+                    continue;
+                }
+                let guard = match shared.sleep.lock() {
+                    Ok(guard) => guard,
+                    Err(_) => return,
+                };
+                // Checked under the lock so a dispatch can't slip in before we wait
+                if shared.pending.load(Ordering::SeqCst) == 0 {
+                    let _woken = shared.wakeup.wait(guard);
+                }
+            });
+        }
+
+        WorkStealing {
+            shared,
+            next: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Dispatcher for WorkStealing {
+    fn dispatch(&self, job: Job) {
+        let shared = &self.shared;
+        let target = self.next.fetch_add(1, Ordering::Relaxed) % shared.queues.len();
+        // Counted before the push so a fast thief can never take the count below zero
+        shared.pending.fetch_add(1, Ordering::SeqCst);
+        match shared.queues[target].lock() {
+            Ok(mut queue) => queue.push_back(job),
+            Err(_) => {
+                shared.pending.fetch_sub(1, Ordering::SeqCst);
+                return;
+            }
+        }
+
+        let _guard = shared.sleep.lock();
+        shared.wakeup.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    fn survives_a_panicking_job(dispatcher: &dyn Dispatcher) {
+        let (sender, receiver) = mpsc::channel();
+        dispatcher.dispatch(Box::new(|| panic!("job blew up")));
+        for i in 0..4 {
+            let sender = sender.clone();
+            dispatcher.dispatch(Box::new(move || sender.send(i).unwrap()));
+        }
+        let mut done: Vec<i32> = (0..4)
+            .map(|_| receiver.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        done.sort();
+        assert_eq!(done, [0, 1, 2, 3]);
+    }
+
+    fn workers(pool: &ThreadPool) -> usize {
+        pool.shared.state.lock().unwrap().workers
+    }
+
+    fn wait_for_workers(pool: &ThreadPool, expected: usize) {
+        for _ in 0..500 {
+            if workers(pool) == expected {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(workers(pool), expected);
+    }
+
+    #[test]
+    fn pool_grows_under_load_and_shrinks_back_when_idle() {
+        let pool = ThreadPool::with_idle_timeout(1, 3, Duration::from_millis(100));
+        assert_eq!(workers(&pool), 1);
+
+        let (release, blocked) = mpsc::channel::<()>();
+        let blocked = Arc::new(Mutex::new(blocked));
+        let (started, running) = mpsc::channel();
+        for _ in 0..5 {
+            let blocked = Arc::clone(&blocked);
+            let started = started.clone();
+            pool.dispatch(Box::new(move || {
+                started.send(()).unwrap();
+                let _ = blocked.lock().unwrap().recv();
+            }));
+        }
+        // Never past the maximum, however much is queued
+        for _ in 0..3 {
+            running.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        assert_eq!(workers(&pool), 3);
+
+        drop(release);
+        for _ in 0..2 {
+            running.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        wait_for_workers(&pool, 1);
+    }
+
+    fn thread_name(dispatcher: &dyn Dispatcher) -> String {
+        let (sender, receiver) = mpsc::channel();
+        dispatcher.dispatch(Box::new(move || {
+            let name = thread::current().name().map(str::to_string);
+            sender.send(name).unwrap();
+        }));
+        receiver
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn threads_are_named_after_what_they_run() {
+        let per_connection = ThreadPerConnection::default();
+        assert_eq!(thread_name(&per_connection), "http-conn-0");
+        assert_eq!(thread_name(&per_connection), "http-conn-1");
+        assert!(thread_name(&ThreadPool::new(1, 1)).starts_with("http-worker-"));
+        assert_eq!(thread_name(&WorkStealing::new(1)), "http-worker-0");
+    }
+
+    #[test]
+    fn pool_worker_survives_a_panicking_job() {
+        survives_a_panicking_job(&ThreadPool::new(1, 1));
+    }
+
+    #[test]
+    fn stealing_worker_survives_a_panicking_job() {
+        survives_a_panicking_job(&WorkStealing::new(1));
+    }
+}
@@ -0,0 +1,200 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+/// In-memory copies of served files, invalidated when the file's mtime moves
+/// or, if configured, once an entry has been held for longer than the TTL.
+pub struct FileCache {
+    inner: Mutex<CacheInner>,
+    ttl: Option<Duration>,
+    max_bytes: Option<u64>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct CacheInner {
+    entries: HashMap<PathBuf, CacheEntry>,
+    bytes: u64,
+}
+
+struct CacheEntry {
+    contents: Vec<u8>,
+    modified: SystemTime,
+    loaded_at: Instant,
+}
+
+pub struct CacheStats {
+    pub entries: usize,
+    pub bytes: u64,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl FileCache {
+    pub fn new(ttl: Option<Duration>, max_bytes: Option<u64>) -> FileCache {
+        FileCache {
+            inner: Mutex::new(CacheInner::default()),
+            ttl,
+            max_bytes,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the file's contents along with how long they've been held,
+    /// the age being `None` when they had to come from disk.
+    pub fn read(&self, path: &Path) -> io::Result<(Vec<u8>, Option<Duration>)> {
+        let modified = fs::metadata(path)?.modified()?;
+
+        if let Some(entry) = self.lock().entries.get(path) {
+            let age = entry.loaded_at.elapsed();
+            let expired = self.ttl.is_some_and(|ttl| age >= ttl);
+            if entry.modified == modified && !expired {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok((entry.contents.clone(), Some(age)));
+            }
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let contents = fs::read(path)?;
+        self.insert(path, contents.clone(), modified);
+        Ok((contents, None))
+    }
+
+    /// Loads a file ahead of any request for it, returning the bytes cached
+    /// or `None` if it didn't fit in what's left of the size limit.
+    pub fn preload(&self, path: &Path) -> io::Result<Option<u64>> {
+        let modified = fs::metadata(path)?.modified()?;
+        let contents = fs::read(path)?;
+        let size = contents.len() as u64;
+        Ok(self.insert(path, contents, modified).then_some(size))
+    }
+
+    /// Drops a path we know has just changed, mtime granularity being too
+    /// coarse to rely on for back-to-back writes.
+    pub fn invalidate(&self, path: &Path) {
+        let mut inner = self.lock();
+        if let Some(entry) = inner.entries.remove(path) {
+            inner.bytes -= entry.contents.len() as u64;
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let inner = self.lock();
+        CacheStats {
+            entries: inner.entries.len(),
+            bytes: inner.bytes,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    fn insert(&self, path: &Path, contents: Vec<u8>, modified: SystemTime) -> bool {
+        let mut inner = self.lock();
+        let replaced = inner
+            .entries
+            .get(path)
+            .map_or(0, |entry| entry.contents.len() as u64);
+        let size = contents.len() as u64;
+        if self
+            .max_bytes
+            .is_some_and(|max_bytes| inner.bytes - replaced + size > max_bytes)
+        {
+            // A stale copy we can't refresh is no use to anyone
+            if inner.entries.remove(path).is_some() {
+                inner.bytes -= replaced;
+            }
+            return false;
+        }
+
+        inner.bytes = inner.bytes - replaced + size;
+        inner.entries.insert(
+            path.to_path_buf(),
+            CacheEntry {
+                contents,
+                modified,
+                loaded_at: Instant::now(),
+            },
+        );
+        true
+    }
+
+    fn lock(&self) -> MutexGuard<'_, CacheInner> {
+        // A panic mid-insert can't leave an entry half written, so poisoning is harmless
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    fn scratch_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("file-cache-{}-{}", process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn second_read_is_a_hit_with_an_age() {
+        let path = scratch_file("hit", "cached");
+        let cache = FileCache::new(None, None);
+
+        let (contents, age) = cache.read(&path).unwrap();
+        assert_eq!(contents, b"cached");
+        assert!(age.is_none());
+        let (contents, age) = cache.read(&path).unwrap();
+        assert_eq!(contents, b"cached");
+        assert!(age.is_some());
+
+        let stats = cache.stats();
+        assert_eq!(
+            (stats.entries, stats.bytes, stats.hits, stats.misses),
+            (1, 6, 1, 1)
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn entries_past_the_ttl_are_reloaded() {
+        let path = scratch_file("ttl", "old");
+        let cache = FileCache::new(Some(Duration::ZERO), None);
+        cache.read(&path).unwrap();
+        let (_, age) = cache.read(&path).unwrap();
+        assert!(age.is_none());
+        assert_eq!(cache.stats().hits, 0);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn invalidated_entries_come_from_disk() {
+        let path = scratch_file("invalidate", "before");
+        let cache = FileCache::new(None, None);
+        cache.read(&path).unwrap();
+        fs::write(&path, "after").unwrap();
+        cache.invalidate(&path);
+        assert_eq!(cache.read(&path).unwrap().0, b"after");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn files_past_the_size_limit_are_not_kept() {
+        let path = scratch_file("limit", "too big");
+        let cache = FileCache::new(None, Some(3));
+        assert_eq!(cache.read(&path).unwrap().0, b"too big");
+        assert_eq!(cache.preload(&path).unwrap(), None);
+        assert_eq!(cache.stats().entries, 0);
+        fs::remove_file(path).unwrap();
+    }
+}
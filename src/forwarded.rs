@@ -0,0 +1,198 @@
+//! Recovering the original client from what a reverse proxy tells us, via
+//! RFC 7239 `Forwarded` or the older `X-Forwarded-For`/`X-Forwarded-Proto`.
+
+use std::net::IpAddr;
+
+/// What the nearest trusted proxy says about the request before it. Either
+/// part may be missing if the proxy didn't report it, or reported something
+/// that isn't a node or a scheme.
+#[derive(Default)]
+pub struct ForwardedInfo {
+    pub client: Option<String>,
+    pub proto: Option<String>,
+}
+
+/// `Forwarded` wins when present; the `X-Forwarded-*` pair is only consulted
+/// if it doesn't say anything we can use.
+pub fn parse(
+    forwarded: Option<&str>,
+    x_forwarded_for: Option<&str>,
+    x_forwarded_proto: Option<&str>,
+) -> ForwardedInfo {
+    if let Some(info) = forwarded.map(parse_forwarded) {
+        if info.client.is_some() || info.proto.is_some() {
+            return info;
+        }
+    }
+
+    ForwardedInfo {
+        client: x_forwarded_for
+            .and_then(first_element)
+            .filter(|client| is_node(client))
+            .map(str::to_string),
+        proto: x_forwarded_proto
+            .and_then(first_element)
+            .filter(|proto| is_scheme(proto))
+            .map(str::to_ascii_lowercase),
+    }
+}
+
+fn parse_forwarded(forwarded: &str) -> ForwardedInfo {
+    let mut info = ForwardedInfo::default();
+    // Every proxy on the way appends an element, the first describes the client
+    let element = match first_element(forwarded) {
+        Some(element) => element,
+        None => return info,
+    };
+
+    for pair in element.split(';') {
+        let (name, value) = match pair.split_once('=') {
+            Some(pair) => pair,
+            None => continue,
+        };
+        let value = value.trim().trim_matches('"');
+        match name.trim().to_ascii_lowercase().as_str() {
+            "for" => {
+                info.client = Some(strip_port(value))
+                    .filter(|client| is_node(client))
+                    .map(str::to_string)
+            }
+            "proto" => {
+                info.proto = Some(value)
+                    .filter(|proto| is_scheme(proto))
+                    .map(str::to_ascii_lowercase)
+            }
+            _ => {}
+        }
+    }
+    info
+}
+
+fn first_element(list: &str) -> Option<&str> {
+    list.split(',')
+        .next()
+        .map(str::trim)
+        .filter(|element| !element.is_empty())
+}
+
+/// Nodes may carry a port, which is why IPv6 addresses come in brackets.
+fn strip_port(node: &str) -> &str {
+    if let Some(bracketed) = node.strip_prefix('[') {
+        return bracketed.split(']').next().unwrap_or_default();
+    }
+    node.split(':').next().unwrap_or_default()
+}
+
+/// An address, `unknown` or an obfuscated `_name`, per RFC 7239. These end
+/// up in the access log, so nothing else gets through.
+fn is_node(node: &str) -> bool {
+    node.parse::<IpAddr>().is_ok()
+        || node.eq_ignore_ascii_case("unknown")
+        || node.strip_prefix('_').is_some_and(|name| {
+            !name.is_empty()
+                && name
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'_' | b'-'))
+        })
+}
+
+/// A URI scheme, per RFC 3986.
+fn is_scheme(scheme: &str) -> bool {
+    scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        && scheme
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'+' | b'-' | b'.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(
+        forwarded: Option<&str>,
+        xff: Option<&str>,
+        xfp: Option<&str>,
+    ) -> (Option<String>, Option<String>) {
+        let info = parse(forwarded, xff, xfp);
+        (info.client, info.proto)
+    }
+
+    fn some(client: &str, proto: &str) -> (Option<String>, Option<String>) {
+        (Some(client.to_string()), Some(proto.to_string()))
+    }
+
+    #[test]
+    fn forwarded_takes_the_first_element() {
+        assert_eq!(
+            info(
+                Some("for=192.0.2.60;proto=HTTPS;by=203.0.113.43, for=198.51.100.17"),
+                None,
+                None
+            ),
+            some("192.0.2.60", "https")
+        );
+        assert_eq!(
+            info(Some(r#"For="[2001:db8:cafe::17]:4711""#), None, None),
+            (Some("2001:db8:cafe::17".to_string()), None)
+        );
+        assert_eq!(
+            info(Some("for=192.0.2.43:8080"), None, None),
+            (Some("192.0.2.43".to_string()), None)
+        );
+    }
+
+    #[test]
+    fn forwarded_wins_over_x_forwarded() {
+        assert_eq!(
+            info(
+                Some("for=192.0.2.1;proto=http"),
+                Some("10.0.0.1"),
+                Some("https")
+            ),
+            some("192.0.2.1", "http")
+        );
+    }
+
+    #[test]
+    fn x_forwarded_is_the_fallback() {
+        assert_eq!(
+            info(None, Some("203.0.113.7, 10.0.0.1"), Some("HTTPS, http")),
+            some("203.0.113.7", "https")
+        );
+        // A Forwarded header with nothing we can use doesn't hide them
+        assert_eq!(
+            info(Some("by=10.0.0.2"), Some("203.0.113.7"), None),
+            (Some("203.0.113.7".to_string()), None)
+        );
+        assert_eq!(info(None, Some(" , 10.0.0.1"), None), (None, None));
+        assert_eq!(info(None, None, None), (None, None));
+    }
+
+    #[test]
+    fn anything_but_nodes_and_schemes_is_dropped() {
+        assert_eq!(
+            info(
+                None,
+                Some(r#"1.2.3.4 http "GET /admin HTTP/1.1" 200"#),
+                None
+            ),
+            (None, None)
+        );
+        assert_eq!(
+            info(Some("for=\"9.9.9.9\rFAKE\""), None, None),
+            (None, None)
+        );
+        assert_eq!(
+            info(Some("for=unknown;proto=http\ns"), None, Some("https")),
+            (Some("unknown".to_string()), None)
+        );
+        assert_eq!(
+            info(Some("for=_hidden-1"), None, None),
+            (Some("_hidden-1".to_string()), None)
+        );
+        assert_eq!(
+            info(None, Some("2001:db8::1"), Some("h t t p")),
+            (Some("2001:db8::1".to_string()), None)
+        );
+    }
+}
@@ -1,5 +1,15 @@
-use clap::Parser;
-use nom::FindSubstring;
+mod acl;
+mod digest;
+mod dispatcher;
+mod file_cache;
+mod forwarded;
+mod path_locks;
+
+use acl::IpNet;
+use clap::{Parser, ValueEnum};
+use dispatcher::{Dispatcher, ThreadPerConnection, ThreadPool, WorkStealing};
+use file_cache::FileCache;
+use path_locks::PathLocks;
 use std::{
     // Error handling
     error::Error,
@@ -8,13 +18,67 @@ use std::{
     // IO related
     io::{BufRead, BufReader, BufWriter, Read, Write},
     // Networking related
-    net::{TcpListener, TcpStream},
-    path::PathBuf,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream},
+    path::{Component, Path, PathBuf},
+    process,
+    str::FromStr,
+    // Shared state between the accept loop and the signal watcher
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+/// Every method registered by RFC 7231 and RFC 5789.
+const KNOWN_METHODS: [&str; 9] = [
+    "GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH",
+];
+
+/// Served at /favicon.ico unless the served directory has its own.
+const DEFAULT_FAVICON: &[u8] = include_bytes!("../assets/favicon.ico");
+const FAVICON_MAX_AGE: u64 = 7 * 24 * 60 * 60;
+
+/// Bodies are buffered in memory, so anything claiming to be bigger is turned away.
+const MAX_BODY_BYTES: usize = 1024 * 1024 * 1024;
+/// Room for a chunk size plus any extensions; a real size line is a few bytes.
+const MAX_CHUNK_LINE: u64 = 4096;
+
+const BIND_ADDRESS: &str = "127.0.0.1";
+const PORT: u16 = 4221;
+
 const GET: &str = "GET";
 const POST: &str = "POST";
-const USER_AGENT: &str = "User-Agent:";
+const HEAD: &str = "HEAD";
+const PATCH: &str = "PATCH";
+const OPTIONS: &str = "OPTIONS";
+const DELETE: &str = "DELETE";
+const USER_AGENT: &str = "User-Agent";
+const CONTENT_TYPE: &str = "Content-Type";
+const CONTENT_LENGTH: &str = "Content-Length";
+const TRANSFER_ENCODING: &str = "Transfer-Encoding";
+const AUTHORIZATION: &str = "Authorization";
+const PROXY_AUTHORIZATION: &str = "Proxy-Authorization";
+const COOKIE: &str = "Cookie";
+const SET_COOKIE: &str = "Set-Cookie";
+const CACHE_CONTROL: &str = "Cache-Control";
+const RETRY_AFTER: &str = "Retry-After";
+const ETAG: &str = "ETag";
+const IF_NONE_MATCH: &str = "If-None-Match";
+const CONTENT_MD5: &str = "Content-MD5";
+const DIGEST: &str = "Digest";
+const CONNECTION: &str = "Connection";
+const KEEP_ALIVE: &str = "Keep-Alive";
+const UPLOAD_OFFSET: &str = "Upload-Offset";
+const LOCATION: &str = "Location";
+const LINK: &str = "Link";
+const ACCEPT_ENCODING: &str = "Accept-Encoding";
+const EXPECT: &str = "Expect";
+const ALLOW: &str = "Allow";
+const FORWARDED: &str = "Forwarded";
+const X_FORWARDED_FOR: &str = "X-Forwarded-For";
+const X_FORWARDED_PROTO: &str = "X-Forwarded-Proto";
 
 #[derive(Parser)]
 #[clap(
@@ -25,218 +89,3100 @@ const USER_AGENT: &str = "User-Agent:";
 )]
 struct Args {
     #[clap(long, short)]
-    directory: PathBuf,
+    directory: Option<PathBuf>,
+
+    /// Write the server's PID to this file on startup
+    #[clap(long)]
+    pid_file: Option<PathBuf>,
+
+    /// Overwrite an existing PID file instead of refusing to start
+    #[clap(long)]
+    pid_file_overwrite: bool,
+
+    /// Seconds to let in-flight connections finish once a shutdown signal arrives
+    #[clap(long, default_value_t = 10)]
+    shutdown_grace_secs: u64,
+
+    /// Accept requests carrying both Transfer-Encoding and Content-Length,
+    /// ignoring the Content-Length, instead of rejecting them
+    #[clap(long)]
+    lenient_framing: bool,
+
+    /// Bearer token required by the /admin routes, which are disabled without it
+    #[clap(long)]
+    admin_token: Option<String>,
+
+    /// Cache-Control max-age for served files by extension, e.g. `png=86400` (repeatable)
+    #[clap(long = "cache-rule")]
+    cache_rules: Vec<CacheRule>,
+
+    /// Reject requests that don't carry a non-empty User-Agent header
+    #[clap(long)]
+    require_user_agent: bool,
+
+    /// How accepted connections are handed out to threads
+    #[clap(long, value_enum, default_value_t = ConcurrencyModel::ThreadPerConnection)]
+    concurrency_model: ConcurrencyModel,
+
+    /// Worker count for the pooled concurrency models (defaults to the CPU count)
+    #[clap(long)]
+    threads: Option<usize>,
+
+    /// Fewest workers the thread pool shrinks back to when idle (defaults to --threads)
+    #[clap(long)]
+    min_threads: Option<usize>,
+
+    /// Most workers the thread pool grows to under load (defaults to --min-threads)
+    #[clap(long)]
+    max_threads: Option<usize>,
+
+    /// Serve the /debug routes, which reflect request details back to the client
+    #[clap(long)]
+    enable_debug_routes: bool,
+
+    /// Keep served files in memory, reloading them when their mtime changes
+    #[clap(long)]
+    file_cache: bool,
+
+    /// Also reload cached files once they've been held this long, mtime or not
+    #[clap(long)]
+    file_cache_ttl_secs: Option<u64>,
+
+    /// Upper bound on the total size of cached file contents
+    #[clap(long)]
+    file_cache_max_bytes: Option<u64>,
+
+    /// How long clients are told to wait before retrying a 503
+    #[clap(long, default_value_t = 5)]
+    retry_after_secs: u64,
+
+    /// Send Retry-After as delta-seconds or as an HTTP-date
+    #[clap(long, value_enum, default_value_t = RetryAfterFormat::Seconds)]
+    retry_after_format: RetryAfterFormat,
+
+    /// Only accept clients from this address or CIDR range (repeatable)
+    #[clap(long = "allow-ip")]
+    allow_ips: Vec<IpNet>,
+
+    /// Refuse clients from this address or CIDR range, even if allowed (repeatable)
+    #[clap(long = "deny-ip")]
+    deny_ips: Vec<IpNet>,
+
+    /// Unfold obsolete multi-line headers instead of rejecting the request
+    #[clap(long)]
+    lenient_folding: bool,
+
+    /// Status sent for /files requests that try to escape the served directory
+    #[clap(long, value_enum, default_value_t = TraversalResponse::NotFound)]
+    traversal_response: TraversalResponse,
+
+    /// Longest request target (path plus query) accepted before answering 414
+    #[clap(long)]
+    max_uri_length: Option<usize>,
+
+    /// Uppercase request methods before matching them, so `get` is served as `GET`
+    #[clap(long)]
+    lenient_methods: bool,
+
+    /// Seconds an idle persistent connection is kept open waiting for the next request
+    // Sockets refuse a zero read timeout, so every connection would fail
+    #[clap(long, default_value_t = 5, value_parser = clap::value_parser!(u64).range(1..))]
+    keep_alive_timeout_secs: u64,
+
+    /// Requests served on one connection before it's closed
+    #[clap(long, default_value_t = 100)]
+    keep_alive_max: usize,
+
+    /// Extra attempts at binding the listening socket before giving up
+    #[clap(long, default_value_t = 0)]
+    bind_retries: u32,
+
+    /// Pause between bind attempts
+    #[clap(long, default_value_t = 500)]
+    bind_retry_delay_ms: u64,
+
+    /// Redirect /files requests for a directory to the same path with a trailing slash
+    #[clap(long)]
+    trailing_slash_redirect: bool,
+
+    /// Report how many requests this instance has served in X-Request-Count
+    #[clap(long)]
+    enable_request_count_header: bool,
+
+    /// Only serve paths under this prefix, 404 for the rest (repeatable)
+    #[clap(long = "allow-path")]
+    allow_paths: Vec<String>,
+
+    /// Take the client address and scheme from Forwarded/X-Forwarded-* headers
+    #[clap(long)]
+    trust_proxy: bool,
+
+    /// Answer OPTIONS for unrouted paths with 404 rather than the server-wide methods
+    #[clap(long, default_value_t = true, action = clap::ArgAction::Set)]
+    options_404_on_unknown: bool,
+
+    /// Write the access log as plain text or one JSON object per line
+    #[clap(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Include request headers in the access log, with credentials redacted
+    #[clap(long)]
+    log_headers: bool,
+
+    /// Include response headers in the access log too
+    #[clap(long)]
+    log_response_headers: bool,
+
+    /// HTML page to send with 503 responses instead of the plain-text default
+    #[clap(long)]
+    busy_page: Option<PathBuf>,
+
+    /// Log progress of uploads to /files/ every this many bytes, 0 to turn it off
+    #[clap(long, default_value_t = 10 * 1024 * 1024)]
+    upload_progress_bytes: usize,
+
+    /// Refuse POSTs to files that already exist with 409 instead of overwriting
+    #[clap(long)]
+    post_create_only: bool,
+
+    /// Let `?follow=1` on a file stream appended bytes as they're written
+    #[clap(long)]
+    enable_tail: bool,
+
+    /// Stop following a file after this many seconds
+    #[clap(long, default_value_t = 300)]
+    tail_max_secs: u64,
+
+    /// Stop following a file after sending this many bytes
+    #[clap(long, default_value_t = 64 * 1024 * 1024)]
+    tail_max_bytes: u64,
+
+    /// Answer requests for directories under /files/ with an HTML index
+    #[clap(long)]
+    enable_listing: bool,
+
+    /// Truncate names longer than this in directory listings
+    #[clap(long, default_value_t = 255)]
+    listing_name_maxlen: usize,
+
+    /// List at most this many entries of a directory
+    #[clap(long, default_value_t = 1000)]
+    listing_max_entries: usize,
+
+    /// Reject GET, HEAD and DELETE requests that carry a body with 400
+    #[clap(long)]
+    reject_get_body: bool,
+
+    /// Name sent in X-Served-By to tell instances apart, the hostname by default
+    #[clap(long)]
+    instance_id: Option<String>,
+
+    /// Serve /files/ for requests to this Host from its own directory, as host=dir (repeatable)
+    #[clap(long = "vhost")]
+    vhosts: Vec<VirtualHost>,
+
+    /// Status for a Host no vhost matches when there's no --directory to fall back on
+    #[clap(long, value_enum, default_value_t = VhostNoMatch::NotFound)]
+    vhost_no_match: VhostNoMatch,
+
+    /// Refuse to serve or accept files under /files/ larger than this many bytes
+    #[clap(long)]
+    max_file_size: Option<u64>,
+
+    /// Send Link preload hints with files of an extension, as ext=/asset,/asset (repeatable)
+    #[clap(long = "preload")]
+    preload_rules: Vec<PreloadRule>,
+
+    /// Listen here instead of 127.0.0.1:4221, as addr:port[:all|public|admin] (repeatable)
+    #[clap(long)]
+    listen: Vec<Listen>,
+
+    /// File of `Name: Value` lines to add to every response that doesn't set them itself
+    #[clap(long)]
+    headers_file: Option<PathBuf>,
+
+    /// Content-Security-Policy to send with HTML responses
+    #[clap(long, value_parser = parse_csp)]
+    csp: Option<String>,
+
+    /// Send the policy on every response, not just HTML
+    #[clap(long)]
+    csp_all_responses: bool,
+
+    /// Only report policy violations, via Content-Security-Policy-Report-Only
+    #[clap(long)]
+    csp_report_only: bool,
+
+    /// Close keep-alive connections once their responses add up to this many bytes
+    #[clap(long)]
+    max_bytes_per_connection: Option<u64>,
+
+    /// Send a file's `.gz` sibling instead to clients that accept gzip
+    #[clap(long)]
+    precompressed: bool,
 }
 
-#[derive(Debug)]
-enum StatusLine {
-    Ok(Option<String>, ContentType),
-    Created(ContentType),
+fn parse_csp(policy: &str) -> Result<String, String> {
+    let policy = policy.trim();
+    if policy.is_empty() {
+        return Err("the policy can't be empty".to_string());
+    }
+    if policy.contains(|c: char| c.is_control()) {
+        return Err("the policy can't contain control characters".to_string());
+    }
+    Ok(policy.to_string())
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum TraversalResponse {
+    #[value(name = "404")]
     NotFound,
+    #[value(name = "403")]
+    Forbidden,
 }
 
-#[derive(Debug)]
-enum ContentType {
-    TextPlain,
-    ApplicationOctetStream,
+/// Which routes a listener answers, so internal ones can live on their own port.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ListenerRole {
+    All,
+    /// Everything except /admin/ and /debug/
+    Public,
+    /// Only /admin/, /debug/ and /health
+    Admin,
 }
 
-trait Message {
-    fn get_message(&self) -> Vec<u8>;
+impl ListenerRole {
+    fn serves(self, path: &str) -> bool {
+        let internal = path.starts_with("/admin/") || path.starts_with("/debug/");
+        match self {
+            ListenerRole::All => true,
+            ListenerRole::Public => !internal,
+            ListenerRole::Admin => internal || path == "/health",
+        }
+    }
 }
 
-impl Message for StatusLine {
-    fn get_message(&self) -> Vec<u8> {
-        let (status_code, body, content_type) = match self {
-            StatusLine::Ok(body, content_type) => {
-                let status_code = "200 OK";
-                (status_code, body.clone(), content_type)
-            }
-            StatusLine::Created(content_type) => {
-                let status_code = "201 Created";
-                (status_code, None, content_type)
-            }
-            StatusLine::NotFound => {
-                let status_code = "404 Not Found";
-                return format!("HTTP/1.1 {}\r\n\r\n", status_code).into_bytes();
-            }
-        };
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum VhostNoMatch {
+    #[value(name = "404")]
+    NotFound,
+    #[value(name = "421")]
+    MisdirectedRequest,
+}
 
-        let content_type_str = match content_type {
-            ContentType::TextPlain => "text/plain",
-            ContentType::ApplicationOctetStream => "application/octet-stream",
-        };
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum RetryAfterFormat {
+    Seconds,
+    Date,
+}
 
-        match body {
-            Some(body) => {
-                let content_length = body.len();
-                format!(
-                    "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
-                    status_code, content_type_str, content_length, body
-                )
-                .into_bytes()
-            }
-            None => format!(
-                "HTTP/1.1 {}\r\nContent-Type: {}\r\n\r\n",
-                status_code, content_type_str
-            )
-            .into_bytes(),
-        }
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+/// The name a `ValueEnum` flag value is spelled with on the command line.
+trait ValueName {
+    fn value_name(&self) -> String;
+}
+
+impl<T: ValueEnum> ValueName for T {
+    fn value_name(&self) -> String {
+        self.to_possible_value()
+            .map(|value| value.get_name().to_string())
+            .unwrap_or_default()
     }
 }
 
-fn main() {
-    if let Err(e) = run_server() {
-        eprintln!("error: {}", e);
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ConcurrencyModel {
+    ThreadPerConnection,
+    Pool,
+    WorkStealing,
+}
+
+#[derive(Clone, Debug)]
+struct CacheRule {
+    extension: String,
+    max_age: u64,
+}
+
+impl FromStr for CacheRule {
+    type Err = String;
+
+    fn from_str(rule: &str) -> Result<CacheRule, String> {
+        let (extension, max_age) = rule
+            .split_once('=')
+            .ok_or_else(|| format!("expected ext=seconds, got `{}`", rule))?;
+        let max_age = max_age
+            .parse()
+            .map_err(|_| format!("invalid max-age `{}`", max_age))?;
+        Ok(CacheRule {
+            extension: extension.trim_start_matches('.').to_ascii_lowercase(),
+            max_age,
+        })
     }
 }
 
-fn run_server() -> Result<(), Box<dyn Error>> {
-    let listener = TcpListener::bind("127.0.0.1:4221")?;
-    println!("Server up!");
+/// Assets to hint with `Link: rel=preload` whenever a file with the given
+/// extension is served, parsed from `ext=/asset,/asset`.
+#[derive(Clone, Debug)]
+struct PreloadRule {
+    extension: String,
+    assets: Vec<String>,
+}
+
+impl FromStr for PreloadRule {
+    type Err = String;
+
+    fn from_str(rule: &str) -> Result<PreloadRule, String> {
+        let (extension, assets) = rule
+            .split_once('=')
+            .ok_or_else(|| format!("expected ext=/asset,/asset, got `{}`", rule))?;
+        let assets: Vec<String> = assets
+            .split(',')
+            .map(str::trim)
+            .filter(|asset| !asset.is_empty())
+            .map(str::to_string)
+            .collect();
+        // Anything that could break out of the <...> would corrupt the header
+        if let Some(asset) = assets
+            .iter()
+            .find(|asset| asset.contains(|c: char| c == '>' || c.is_control()))
+        {
+            return Err(format!("invalid asset `{}`", asset));
+        }
+        Ok(PreloadRule {
+            extension: extension.trim_start_matches('.').to_ascii_lowercase(),
+            assets,
+        })
+    }
+}
+
+/// An address to accept connections on, as `addr:port[:role]`.
+#[derive(Clone, Debug)]
+struct Listen {
+    addr: SocketAddr,
+    role: ListenerRole,
+}
+
+impl FromStr for Listen {
+    type Err = String;
 
-    for stream in listener.incoming() {
-        if let Ok(stream) = stream {
-            std::thread::spawn(move || {
-                let _ = handle_connection(stream);
+    fn from_str(listen: &str) -> Result<Listen, String> {
+        if let Ok(addr) = listen.parse() {
+            return Ok(Listen {
+                addr,
+                role: ListenerRole::All,
             });
         }
+        let (addr, role) = listen
+            .rsplit_once(':')
+            .ok_or_else(|| format!("expected addr:port[:role], got `{}`", listen))?;
+        Ok(Listen {
+            addr: addr
+                .parse()
+                .map_err(|_| format!("invalid address `{}`", addr))?,
+            role: ListenerRole::from_str(role, true)
+                .map_err(|_| format!("invalid role `{}`", role))?,
+        })
     }
+}
 
-    Ok(())
+impl std::fmt::Display for Listen {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.addr, self.role.value_name())
+    }
 }
 
-fn handle_connection(stream: TcpStream) -> Result<(), Box<dyn Error>> {
-    let (mut buf_reader, mut buf_writer) = setup_streams(stream)?;
-    let mut http_request = read_request(&mut buf_reader)?;
-    process_request_body(&mut buf_reader, &mut http_request)?;
-    let response = generate_response(&http_request)?;
-    println!("THE RESPONSE IS HERE: {:#?}", response);
-    send_response(&mut buf_writer, &response)?;
-    Ok(())
+#[derive(Clone, Debug)]
+struct VirtualHost {
+    host: String,
+    directory: PathBuf,
 }
 
-fn setup_streams(
-    stream: TcpStream,
-) -> Result<(BufReader<TcpStream>, BufWriter<TcpStream>), Box<dyn Error>> {
-    let read_stream = stream.try_clone()?;
-    let write_stream = stream.try_clone()?;
-    let buf_reader = BufReader::new(read_stream);
-    let buf_writer = BufWriter::new(write_stream);
-    Ok((buf_reader, buf_writer))
+impl FromStr for VirtualHost {
+    type Err = String;
+
+    fn from_str(vhost: &str) -> Result<VirtualHost, String> {
+        let (host, directory) = vhost
+            .split_once('=')
+            .filter(|(host, directory)| !host.is_empty() && !directory.is_empty())
+            .ok_or_else(|| format!("expected host=dir, got `{}`", vhost))?;
+        Ok(VirtualHost {
+            host: host.to_ascii_lowercase(),
+            directory: PathBuf::from(directory),
+        })
+    }
 }
 
-fn read_request(buf_reader: &mut BufReader<TcpStream>) -> Result<Vec<String>, Box<dyn Error>> {
-    let http_request: Vec<_> = buf_reader
-        .by_ref()
-        .lines()
-        .map(|result| result.unwrap())
-        .take_while(|line| !line.is_empty())
-        .collect();
-    Ok(http_request)
+/// State shared by every connection for the lifetime of the server.
+struct ServerState {
+    args: Args,
+    /// Cleared by `POST /admin/drain` so load balancers take us out of rotation
+    ready: AtomicBool,
+    shutdown: AtomicBool,
+    file_cache: Option<FileCache>,
+    requests_served: AtomicU64,
+    /// Read once at startup so a missing page is caught before we're busy
+    busy_page: Option<Vec<u8>>,
+    instance_id: String,
+    /// From `--headers-file`, validated at startup
+    default_headers: Headers,
+    /// Held across a PATCH's offset check and append, and a POST's write
+    upload_locks: PathLocks,
+    /// Where the listeners actually ended up, OS-picked ports included
+    bound: Vec<Listen>,
+    /// Connections dispatched but not yet finished, so shutdown can wait on them
+    in_flight: Mutex<usize>,
+    all_finished: Condvar,
 }
 
-fn process_request_body(
-    buf_reader: &mut BufReader<TcpStream>,
-    http_request: &mut Vec<String>,
-) -> Result<(), Box<dyn Error>> {
-    let content_length = find_content_length(http_request);
-    let mut buffer = vec![0; content_length];
-    buf_reader.read_exact(&mut buffer)?;
-    let body = String::from_utf8_lossy(&buffer);
-    http_request.push(body.trim().to_string());
-    Ok(())
+/// Counts a connection as in flight from dispatch until it's dropped, which
+/// also happens if its job panics or never gets to run.
+struct InFlight(Arc<ServerState>);
+
+impl InFlight {
+    fn start(state: &Arc<ServerState>) -> InFlight {
+        *lock_in_flight(state) += 1;
+        InFlight(Arc::clone(state))
+    }
 }
 
-fn generate_response(http_request: &[String]) -> Result<StatusLine, Box<dyn Error>> {
-    if let Some(request_line) = http_request.get(0) {
-        match resolve_path(request_line) {
-            Some("/") => Ok(StatusLine::Ok(None, ContentType::TextPlain)),
-            Some(path) => Ok(path_to_status_line(path, http_request)),
-            _ => Ok(StatusLine::NotFound),
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let mut in_flight = lock_in_flight(&self.0);
+        *in_flight -= 1;
+        if *in_flight == 0 {
+            self.0.all_finished.notify_all();
         }
-    } else {
-        eprintln!("Received empty request");
-        Err("Empty request received".into())
     }
 }
 
-fn send_response(
-    buf_writer: &mut BufWriter<TcpStream>,
-    response: &StatusLine,
-) -> Result<(), Box<dyn Error>> {
-    buf_writer.write_all(&response.get_message())?;
-    buf_writer.flush()?;
-    Ok(())
+/// A panicking connection can't leave the count itself inconsistent.
+fn lock_in_flight(state: &ServerState) -> std::sync::MutexGuard<'_, usize> {
+    state
+        .in_flight
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
 }
 
-fn path_to_status_line(path: &str, request_data: &[String]) -> StatusLine {
-    if let Some(s) = path.strip_prefix("/echo/") {
-        return StatusLine::Ok(Some(s.to_string()), ContentType::TextPlain);
+/// Blocks until every dispatched connection has finished or `grace` runs out,
+/// returning how many were still going.
+fn wait_for_in_flight(state: &ServerState, grace: Duration) -> usize {
+    let deadline = Instant::now() + grace;
+    let mut in_flight = lock_in_flight(state);
+    while *in_flight > 0 {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        in_flight = match state.all_finished.wait_timeout(in_flight, deadline - now) {
+            Ok((guard, _)) => guard,
+            Err(poisoned) => poisoned.into_inner().0,
+        };
     }
+    *in_flight
+}
 
-    if let Some(file_path) = path.strip_prefix("/files/") {
-        return handle_file_path(file_path, request_data);
+/// Owns the PID file for the lifetime of the server and removes it on drop.
+struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    fn create(path: &Path, overwrite: bool) -> Result<PidFile, Box<dyn Error>> {
+        let mut file = if overwrite {
+            fs::File::create(path)?
+        } else {
+            fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(path)
+                .map_err(|e| format!("pid file {}: {}", path.display(), e))?
+        };
+        writeln!(file, "{}", process::id())?;
+        Ok(PidFile {
+            path: path.to_path_buf(),
+        })
     }
+}
 
-    if path.find_substring("/user-agent").is_some() {
-        return handle_user_agent(request_data);
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
     }
+}
 
-    StatusLine::NotFound
+/// Header name/value pairs in the order they were received.
+type Headers = Vec<(String, String)>;
+
+#[derive(Debug)]
+struct HttpRequest {
+    /// Exactly as received, minus the line ending
+    request_line: String,
+    /// Separate from the request line so lenient parsing can normalise it
+    method: String,
+    headers: Headers,
+    body: Vec<u8>,
+    /// Headers sent after a chunked body, kept apart from the real headers
+    trailers: Headers,
+    /// Whether any header was continued onto a following line
+    obs_folded: bool,
 }
 
-fn handle_file_path(file_path: &str, request_data: &[String]) -> StatusLine {
-    let args = Args::parse();
-    let full_path = args.directory.join(file_path);
+impl HttpRequest {
+    fn method(&self) -> Option<&str> {
+        Some(self.method.as_str()).filter(|method| !method.is_empty())
+    }
 
-    let request_type = request_data
-        .iter()
-        .next()
-        .and_then(|s| s.split_whitespace().next());
+    /// HEAD is routed like GET; only the serializer needs to know the difference.
+    fn is_head(&self) -> bool {
+        self.method() == Some(HEAD)
+    }
 
-    match request_type {
-        Some(GET) => fs::read_to_string(&full_path)
-            .map(|file_contents| {
-                StatusLine::Ok(Some(file_contents), ContentType::ApplicationOctetStream)
-            })
-            .unwrap_or(StatusLine::NotFound),
-        Some(POST) => {
-            fs::write(&full_path, request_data.last().unwrap_or(&String::new())).unwrap();
-            StatusLine::Created(ContentType::TextPlain)
+    /// The request target as sent, query string included.
+    fn target(&self) -> Option<&str> {
+        resolve_path(&self.request_line)
+    }
+
+    fn path(&self) -> Option<&str> {
+        self.target()
+            .map(|target| target.split_once('?').map_or(target, |(path, _)| path))
+    }
+
+    fn query(&self) -> Option<&str> {
+        self.target()?.split_once('?').map(|(_, query)| query)
+    }
+
+    /// The value of the first `name=value` pair in the query, `""` if bare.
+    fn query_param(&self, name: &str) -> Option<&str> {
+        self.query()?.split('&').find_map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (key == name).then_some(value)
+        })
+    }
+
+    fn version(&self) -> Option<&str> {
+        self.request_line.split_whitespace().nth(2)
+    }
+
+    /// Looks up a header value, header names being case-insensitive.
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Debug)]
+enum StatusLine {
+    Ok(Option<Vec<u8>>, ContentType),
+    Created(ContentType),
+    NoContent,
+    MovedPermanently,
+    NotModified,
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    Conflict,
+    PayloadTooLarge,
+    UriTooLong,
+    MisdirectedRequest,
+    InternalServerError,
+    NotImplemented,
+    /// The body is filled in by `finalize_response` from the busy page
+    ServiceUnavailable(Option<Vec<u8>>, ContentType),
+}
+
+impl StatusLine {
+    fn status_code(&self) -> &'static str {
+        match self {
+            StatusLine::Ok(..) => "200 OK",
+            StatusLine::Created(_) => "201 Created",
+            StatusLine::NoContent => "204 No Content",
+            StatusLine::MovedPermanently => "301 Moved Permanently",
+            StatusLine::NotModified => "304 Not Modified",
+            StatusLine::BadRequest => "400 Bad Request",
+            StatusLine::Unauthorized => "401 Unauthorized",
+            StatusLine::Forbidden => "403 Forbidden",
+            StatusLine::NotFound => "404 Not Found",
+            StatusLine::Conflict => "409 Conflict",
+            StatusLine::MisdirectedRequest => "421 Misdirected Request",
+            StatusLine::PayloadTooLarge => "413 Payload Too Large",
+            StatusLine::UriTooLong => "414 URI Too Long",
+            StatusLine::InternalServerError => "500 Internal Server Error",
+            StatusLine::NotImplemented => "501 Not Implemented",
+            StatusLine::ServiceUnavailable(..) => "503 Service Unavailable",
+        }
+    }
+
+    fn content_type(&self) -> Option<&ContentType> {
+        match self {
+            StatusLine::Ok(_, content_type) | StatusLine::Created(content_type) => {
+                Some(content_type)
+            }
+            StatusLine::ServiceUnavailable(Some(_), content_type) => Some(content_type),
+            _ => None,
+        }
+    }
+
+    fn body(&self) -> Option<&[u8]> {
+        match self {
+            StatusLine::Ok(body, _) | StatusLine::ServiceUnavailable(body, _) => body.as_deref(),
+            _ => None,
         }
-        _ => StatusLine::NotFound,
+    }
+
+    /// 204 and 304 end at the blank line, so describing a body makes no sense.
+    fn is_bodiless(&self) -> bool {
+        matches!(self, StatusLine::NoContent | StatusLine::NotModified)
     }
 }
 
-fn handle_user_agent(request_data: &[String]) -> StatusLine {
-    request_data
-        .iter()
-        .find(|header| header.contains(USER_AGENT))
-        .map_or(StatusLine::NotFound, |user_agent| {
-            StatusLine::Ok(
-                user_agent
-                    .strip_prefix(USER_AGENT)
-                    .map(|s| s.trim().to_string()),
-                ContentType::TextPlain,
-            )
-        })
+#[derive(Debug)]
+enum ContentType {
+    TextPlain,
+    TextHtml,
+    TextCss,
+    ApplicationJavascript,
+    ApplicationJson,
+    ApplicationWasm,
+    ApplicationOctetStream,
+    ImagePng,
+    ImageJpeg,
+    ImageGif,
+    ImageWebp,
+    ImageAvif,
+    ImageSvg,
+    ImageXIcon,
+    FontWoff2,
 }
 
-fn resolve_path(request: &str) -> Option<&str> {
-    let request_header = request.lines().next()?;
-    request_header.split_whitespace().nth(1)
+impl ContentType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ContentType::TextPlain => "text/plain",
+            ContentType::TextHtml => "text/html",
+            ContentType::TextCss => "text/css",
+            ContentType::ApplicationJavascript => "application/javascript",
+            ContentType::ApplicationJson => "application/json",
+            ContentType::ApplicationWasm => "application/wasm",
+            ContentType::ApplicationOctetStream => "application/octet-stream",
+            ContentType::ImagePng => "image/png",
+            ContentType::ImageJpeg => "image/jpeg",
+            ContentType::ImageGif => "image/gif",
+            ContentType::ImageWebp => "image/webp",
+            ContentType::ImageAvif => "image/avif",
+            ContentType::ImageSvg => "image/svg+xml",
+            ContentType::ImageXIcon => "image/x-icon",
+            ContentType::FontWoff2 => "font/woff2",
+        }
+    }
 }
 
-fn find_content_length(headers: &[String]) -> usize {
-    headers
-        .iter()
-        .find_map(|header| {
-            if header.starts_with("Content-Length:") {
-                header.split_whitespace().nth(1)?.parse().ok()
-            } else {
-                None
+/// A status line plus whatever extra headers the route wants sent with it.
+#[derive(Debug)]
+struct HttpResponse {
+    status_line: StatusLine,
+    headers: Vec<(String, String)>,
+    /// A file to keep streaming after the head, as `tail -f` would
+    follow: Option<PathBuf>,
+}
+
+impl HttpResponse {
+    fn with_header(mut self, name: &str, value: impl Into<String>) -> HttpResponse {
+        self.headers.push((name.to_string(), value.into()));
+        self
+    }
+}
+
+impl From<StatusLine> for HttpResponse {
+    fn from(status_line: StatusLine) -> HttpResponse {
+        HttpResponse {
+            status_line,
+            headers: Vec::new(),
+            follow: None,
+        }
+    }
+}
+
+trait Message {
+    /// Writes the whole response, or just its head when answering a HEAD,
+    /// returning how many bytes that came to.
+    fn write_to(&self, writer: &mut impl Write, include_body: bool) -> std::io::Result<usize>;
+}
+
+impl Message for HttpResponse {
+    fn write_to(&self, writer: &mut impl Write, include_body: bool) -> std::io::Result<usize> {
+        let status_line = &self.status_line;
+        let mut message = format!("HTTP/1.1 {}\r\n", status_line.status_code());
+
+        if let Some(content_type) = status_line.content_type() {
+            message.push_str(&format!("{}: {}\r\n", CONTENT_TYPE, content_type.as_str()));
+        }
+        // Every response needs framing for the connection to be reused after it,
+        // unless the route already knows better (HEAD reports the GET length,
+        // followed files are chunked)
+        let explicit_length = self.headers.iter().any(|(name, _)| {
+            name.eq_ignore_ascii_case(CONTENT_LENGTH)
+                || name.eq_ignore_ascii_case(TRANSFER_ENCODING)
+        });
+        match status_line.body() {
+            _ if explicit_length => {}
+            Some(body) => message.push_str(&format!("Content-Length: {}\r\n", body.len())),
+            None if !status_line.is_bodiless() => message.push_str("Content-Length: 0\r\n"),
+            None => {}
+        }
+        for (name, value) in &self.headers {
+            let describes_body = name.eq_ignore_ascii_case(CONTENT_LENGTH)
+                || name.eq_ignore_ascii_case(CONTENT_TYPE);
+            if describes_body && status_line.is_bodiless() {
+                continue;
             }
-        })
-        .unwrap_or(0)
+            message.push_str(&format!("{}: {}\r\n", name, value));
+        }
+
+        message.push_str("\r\n");
+        writer.write_all(message.as_bytes())?;
+        let mut written = message.len();
+        if include_body {
+            let body = status_line.body().unwrap_or_default();
+            writer.write_all(body)?;
+            written += body.len();
+        }
+        Ok(written)
+    }
+}
+
+fn main() {
+    if let Err(e) = run_server() {
+        eprintln!("error: {}", e);
+        // Supervisors only notice a failed start by the exit status
+        process::exit(1);
+    }
+}
+
+fn run_server() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+    let file_cache = args.file_cache.then(|| {
+        FileCache::new(
+            args.file_cache_ttl_secs.map(Duration::from_secs),
+            args.file_cache_max_bytes,
+        )
+    });
+    let busy_page = match &args.busy_page {
+        Some(path) => {
+            Some(fs::read(path).map_err(|e| format!("reading {}: {}", path.display(), e))?)
+        }
+        None => None,
+    };
+    let instance_id = args.instance_id.clone().unwrap_or_else(hostname);
+    let default_headers = match &args.headers_file {
+        Some(path) => read_headers_file(path)?,
+        None => Vec::new(),
+    };
+    let listens = if args.listen.is_empty() {
+        vec![Listen {
+            addr: SocketAddr::new(BIND_ADDRESS.parse()?, PORT),
+            role: ListenerRole::All,
+        }]
+    } else {
+        args.listen.clone()
+    };
+    let mut listeners = Vec::new();
+    let mut bound = Vec::new();
+    for listen in &listens {
+        let listener = bind_with_retries(listen.addr, &args)?;
+        bound.push(Listen {
+            addr: listener.local_addr()?,
+            role: listen.role,
+        });
+        listeners.push((listener, listen.role));
+    }
+    let state = Arc::new(ServerState {
+        args,
+        ready: AtomicBool::new(true),
+        shutdown: AtomicBool::new(false),
+        file_cache,
+        requests_served: AtomicU64::new(0),
+        busy_page,
+        instance_id,
+        default_headers,
+        upload_locks: PathLocks::default(),
+        bound,
+        in_flight: Mutex::new(0),
+        all_finished: Condvar::new(),
+    });
+    let _pid_file = match &state.args.pid_file {
+        Some(path) => Some(PidFile::create(path, state.args.pid_file_overwrite)?),
+        None => None,
+    };
+
+    let addrs = state.bound.iter().map(|listen| listen.addr).collect();
+    spawn_shutdown_watcher(addrs, Arc::clone(&state));
+    let dispatcher = build_dispatcher(&state.args);
+    println!("Server up!");
+
+    thread::scope(|scope| {
+        for (listener, role) in &listeners {
+            let (state, dispatcher) = (&state, &*dispatcher);
+            scope.spawn(move || accept_loop(listener, *role, state, dispatcher));
+        }
+    });
+
+    // Idle keep-alive connections count too; they go once their timeout is up
+    let grace = Duration::from_secs(state.args.shutdown_grace_secs);
+    let abandoned = wait_for_in_flight(&state, grace);
+    if abandoned > 0 {
+        eprintln!("warning: {} connections still open at shutdown", abandoned);
+    }
+    println!("Shutting down");
+    Ok(())
+}
+
+fn accept_loop(
+    listener: &TcpListener,
+    role: ListenerRole,
+    state: &Arc<ServerState>,
+    dispatcher: &dyn Dispatcher,
+) {
+    for stream in listener.incoming().flatten() {
+        if state.shutdown.load(Ordering::SeqCst) {
+            refuse_backlog(stream, listener, role, state);
+            break;
+        }
+        let in_flight = InFlight::start(state);
+        dispatcher.dispatch(Box::new(move || {
+            let _ = handle_connection(stream, role, &in_flight.0);
+        }));
+    }
+}
+
+/// Answers whatever was accepted as we stopped with a 503, on this thread so
+/// it goes out before the process exits instead of being dropped.
+fn refuse_backlog(
+    first: TcpStream,
+    listener: &TcpListener,
+    role: ListenerRole,
+    state: &ServerState,
+) {
+    let _ = handle_connection(first, role, state);
+    if listener.set_nonblocking(true).is_err() {
+        return;
+    }
+    while let Ok((stream, _)) = listener.accept() {
+        if stream.set_nonblocking(false).is_ok() {
+            let _ = handle_connection(stream, role, state);
+        }
+    }
+}
+
+/// Parses `Name: Value` lines, skipping blanks and `#` comments. Framing
+/// headers are refused since a wrong one would corrupt every response.
+fn read_headers_file(path: &Path) -> Result<Headers, String> {
+    let contents =
+        fs::read_to_string(path).map_err(|e| format!("reading {}: {}", path.display(), e))?;
+    let mut headers = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |reason: &str| format!("{}:{}: {}", path.display(), number + 1, reason);
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid("expected `Name: Value`"))?;
+        let name = name.trim();
+        let is_token = !name.is_empty()
+            && name
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte));
+        if !is_token {
+            return Err(invalid("invalid header name"));
+        }
+        if [CONTENT_LENGTH, TRANSFER_ENCODING, CONNECTION]
+            .iter()
+            .any(|framing| name.eq_ignore_ascii_case(framing))
+        {
+            return Err(invalid("framing headers can't be set here"));
+        }
+        // Every response already says what its own body is
+        if name.eq_ignore_ascii_case(CONTENT_TYPE) {
+            return Err(invalid("Content-Type can't be set here"));
+        }
+        // A stray CR would end the header early and start one of its own
+        let value = value.trim();
+        if value.contains(|c: char| c.is_control()) {
+            return Err(invalid("header values can't contain control characters"));
+        }
+        headers.push((name.to_string(), value.to_string()));
+    }
+    Ok(headers)
+}
+
+/// std has no portable hostname lookup, so try what's usually lying around.
+fn hostname() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Binding can fail briefly while a previous instance is still letting go of
+/// the port, so give it a few more chances before bailing out.
+fn bind_with_retries(addr: SocketAddr, args: &Args) -> std::io::Result<TcpListener> {
+    let mut attempt = 0;
+    loop {
+        match TcpListener::bind(addr) {
+            Ok(listener) => return Ok(listener),
+            Err(e) if attempt < args.bind_retries => {
+                attempt += 1;
+                eprintln!(
+                    "bind {} failed ({}), retry {}/{} in {}ms",
+                    addr, e, attempt, args.bind_retries, args.bind_retry_delay_ms
+                );
+                thread::sleep(Duration::from_millis(args.bind_retry_delay_ms));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn build_dispatcher(args: &Args) -> Box<dyn Dispatcher> {
+    let threads = args.threads.unwrap_or_else(|| {
+        thread::available_parallelism()
+            .map(|count| count.get())
+            .unwrap_or(4)
+    });
+    match args.concurrency_model {
+        ConcurrencyModel::ThreadPerConnection => Box::<ThreadPerConnection>::default(),
+        ConcurrencyModel::Pool => {
+            let min_threads = args.min_threads.unwrap_or(threads);
+            let max_threads = args.max_threads.unwrap_or(min_threads);
+            Box::new(ThreadPool::new(min_threads, max_threads))
+        }
+        ConcurrencyModel::WorkStealing => Box::new(WorkStealing::new(threads)),
+    }
+}
+
+/// Waits for SIGINT/SIGTERM on a background thread, flags the shutdown and
+/// pokes every listener so the blocking accept loops notice it.
+fn spawn_shutdown_watcher(addrs: Vec<SocketAddr>, state: Arc<ServerState>) {
+    let watcher = thread::Builder::new().name("shutdown-watcher".to_string());
+    let spawned = watcher.spawn(move || {
+        let runtime = match tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+        {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                eprintln!("error: could not install signal handlers: {}", e);
+                return;
+            }
+        };
+        runtime.block_on(wait_for_shutdown_signal());
+        state.shutdown.store(true, Ordering::SeqCst);
+        for mut addr in addrs {
+            if addr.ip().is_unspecified() {
+                addr.set_ip(match addr {
+                    SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                    SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                });
+            }
+            let _ = TcpStream::connect(addr);
+        }
+    });
+    if let Err(e) = spawned {
+        eprintln!("error: could not start shutdown watcher: {}", e);
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut sigterm) => {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = sigterm.recv() => {}
+            }
+        }
+        Err(_) => {
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_shutdown_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+fn handle_connection(
+    stream: TcpStream,
+    role: ListenerRole,
+    state: &ServerState,
+) -> Result<(), Box<dyn Error>> {
+    let args = &state.args;
+    let peer_addr = stream.peer_addr()?;
+    stream.set_read_timeout(Some(Duration::from_secs(args.keep_alive_timeout_secs)))?;
+    let (mut buf_reader, mut buf_writer) = setup_streams(stream)?;
+    let mut bytes_written = 0;
+
+    for served in 1.. {
+        let mut http_request = match read_request(&mut buf_reader) {
+            Ok(Some(http_request)) => http_request,
+            // Closed between requests, as LB probes that never send anything do
+            Ok(None) => return Ok(()),
+            Err(e) if is_timeout(&*e) || is_disconnect(&*e) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        if args.lenient_methods {
+            http_request.method.make_ascii_uppercase();
+        }
+        let checks = check_running(state)
+            .and_then(|()| check_client(peer_addr, args))
+            .and_then(|()| validate_request(&http_request, args));
+        // A rejected request's body was never read, so the stream is out of sync
+        let mut keep_alive = checks.is_ok() && wants_keep_alive(&http_request);
+        let response = match checks {
+            Ok(()) => {
+                if expects_continue(&http_request) {
+                    buf_writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+                    buf_writer.flush()?;
+                }
+                match process_request_body(&mut buf_reader, &mut http_request, args) {
+                    Ok(()) => generate_response(&http_request, role, state)?,
+                    // Wherever the next request starts, we can't find it now
+                    Err(BodyError::Malformed(rejection)) => {
+                        keep_alive = false;
+                        rejection.into()
+                    }
+                    Err(BodyError::Io(e)) if is_timeout(&e) || is_disconnect(&e) => return Ok(()),
+                    Err(BodyError::Io(e)) => return Err(e.into()),
+                }
+            }
+            Err(rejection) => rejection.into(),
+        };
+
+        let remaining = args.keep_alive_max.saturating_sub(served);
+        keep_alive &= remaining > 0 && !state.shutdown.load(Ordering::SeqCst);
+        // A followed file only ends when a cap is hit, by then the client is done
+        keep_alive &= response.follow.is_none();
+        let response = finalize_response(response, state);
+        let keep_alive_value = format!(
+            "timeout={}, max={}",
+            args.keep_alive_timeout_secs, remaining
+        );
+        // Sized as it would go out, Keep-Alive header and all, so the response
+        // that crosses the limit is the one that says it's the last
+        if let Some(max_bytes) = args.max_bytes_per_connection {
+            let size = response.write_to(&mut std::io::sink(), !http_request.is_head())?
+                + format!("{}: {}\r\n", KEEP_ALIVE, keep_alive_value).len();
+            keep_alive &= ((bytes_written + size) as u64) < max_bytes;
+        }
+        let response = if keep_alive {
+            let response = response.with_header(KEEP_ALIVE, keep_alive_value);
+            // Persistence is opt-in before 1.1, so the client is told it worked
+            if http_request.version() == Some("HTTP/1.1") {
+                response
+            } else {
+                response.with_header(CONNECTION, "keep-alive")
+            }
+        } else {
+            response.with_header(CONNECTION, "close")
+        };
+        println!(
+            "{}",
+            access_log_line(peer_addr, &http_request, &response, args)
+        );
+        let sent = send_response(&mut buf_writer, &response, !http_request.is_head()).and_then(
+            |written| match &response.follow {
+                Some(path) => follow_file(&mut buf_writer, path, args).map(|()| written),
+                None => Ok(written),
+            },
+        );
+        bytes_written += match sent {
+            Ok(written) => written,
+            Err(e) if is_disconnect(&*e) => {
+                // Nobody is reading any more, so don't let the drop flush try again
+                let _unsent = buf_writer.into_parts();
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+
+        if !keep_alive {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Whether the client is holding its body back until we say go. Chunked
+/// uploads have no Content-Length but still have a body to wait for.
+fn expects_continue(http_request: &HttpRequest) -> bool {
+    let has_body =
+        http_request.header(TRANSFER_ENCODING).is_some() || find_content_length(http_request) > 0;
+    http_request.version() == Some("HTTP/1.1")
+        && has_body
+        && http_request
+            .header(EXPECT)
+            .is_some_and(|expect| expect.trim().eq_ignore_ascii_case("100-continue"))
+}
+
+/// HTTP/1.1 connections persist unless the client opts out; 1.0 ones only if it opts in.
+fn wants_keep_alive(http_request: &HttpRequest) -> bool {
+    let connection = http_request.header(CONNECTION).unwrap_or_default();
+    let has_token = |token: &str| {
+        connection
+            .split(',')
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    };
+    match http_request.version() {
+        Some("HTTP/1.1") => !has_token("close"),
+        _ => has_token("keep-alive"),
+    }
+}
+
+fn is_timeout(error: &(dyn Error + 'static)) -> bool {
+    error.downcast_ref::<std::io::Error>().is_some_and(|e| {
+        matches!(
+            e.kind(),
+            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+        )
+    })
+}
+
+/// The peer has stopped reading, which only shows up once we write to it.
+fn is_disconnect(error: &(dyn Error + 'static)) -> bool {
+    error.downcast_ref::<std::io::Error>().is_some_and(|e| {
+        matches!(
+            e.kind(),
+            std::io::ErrorKind::BrokenPipe
+                | std::io::ErrorKind::ConnectionReset
+                | std::io::ErrorKind::ConnectionAborted
+        )
+    })
+}
+
+fn setup_streams(
+    stream: TcpStream,
+) -> Result<(BufReader<TcpStream>, BufWriter<TcpStream>), Box<dyn Error>> {
+    let read_stream = stream.try_clone()?;
+    let write_stream = stream.try_clone()?;
+    let buf_reader = BufReader::new(read_stream);
+    let buf_writer = BufWriter::new(write_stream);
+    Ok((buf_reader, buf_writer))
+}
+
+/// Reads the request line and headers, or `None` if the peer closed the
+/// connection before sending a single byte.
+fn read_request(
+    buf_reader: &mut BufReader<TcpStream>,
+) -> Result<Option<HttpRequest>, Box<dyn Error>> {
+    let (request_line, method) = match read_line_bytes(buf_reader)? {
+        None => return Ok(None),
+        Some(line) => match String::from_utf8(line) {
+            Ok(line) => {
+                let method = line.split_whitespace().next().unwrap_or_default().to_string();
+                (line, method)
+            }
+            // Not a request line we can route, so no method, which gets it a 400
+            Err(e) => (String::from_utf8_lossy(e.as_bytes()).into_owned(), String::new()),
+        },
+    };
+
+    let mut headers: Headers = Vec::new();
+    let mut obs_folded = false;
+    while let Some(line) = read_line_bytes(buf_reader)? {
+        let line = decode_header_line(line);
+        if line.is_empty() {
+            break;
+        }
+        // obs-fold: a line starting with whitespace continues the previous header
+        if line.starts_with([' ', '\t']) {
+            obs_folded = true;
+            if let Some((_, value)) = headers.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+            continue;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+
+    Ok(Some(HttpRequest {
+        method,
+        request_line,
+        headers,
+        body: Vec::new(),
+        trailers: Vec::new(),
+        obs_folded,
+    }))
+}
+
+/// One line without its `\n` or `\r\n`, or `None` once the stream has ended.
+fn read_line_bytes(buf_reader: &mut impl BufRead) -> std::io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    if buf_reader.read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.ends_with(b"\n") {
+        line.pop();
+        if line.ends_with(b"\r") {
+            line.pop();
+        }
+    }
+    Ok(Some(line))
+}
+
+/// Header values should be ASCII but older clients send Latin-1, which any
+/// byte string decodes as, so that's the fallback when it isn't UTF-8.
+fn decode_header_line(line: Vec<u8>) -> String {
+    String::from_utf8(line)
+        .unwrap_or_else(|e| e.into_bytes().into_iter().map(char::from).collect())
+}
+
+fn access_log_line(
+    peer_addr: SocketAddr,
+    http_request: &HttpRequest,
+    response: &HttpResponse,
+    args: &Args,
+) -> String {
+    let (client, scheme) = client_and_scheme(peer_addr, http_request, args);
+    let status = response.status_line.status_code();
+    let request_headers = args
+        .log_headers
+        .then(|| redact_headers(&http_request.headers));
+    let response_headers = args
+        .log_response_headers
+        .then(|| redact_headers(&response.headers));
+
+    match args.log_format {
+        LogFormat::Text => {
+            // Whatever the client sent can't be allowed to forge extra log lines
+            let mut line = format!(
+                "{} {} \"{}\" {}",
+                client,
+                scheme,
+                escape_control(&http_request.request_line),
+                status
+            );
+            for (label, headers) in [("request", request_headers), ("response", response_headers)] {
+                if let Some(headers) = headers {
+                    let headers = headers
+                        .iter()
+                        .map(|(name, value)| format!("{}: {}", name, value))
+                        .collect::<Vec<_>>()
+                        .join("; ");
+                    line.push_str(&format!(" {}_headers={:?}", label, headers));
+                }
+            }
+            line
+        }
+        LogFormat::Json => {
+            let mut object = JsonObject::new()
+                .string("client", &client)
+                .string("scheme", &scheme)
+                .string("request_line", &http_request.request_line)
+                .string("status", status);
+            if let Some(headers) = request_headers {
+                object = object.raw("request_headers", json_object(&headers));
+            }
+            if let Some(headers) = response_headers {
+                object = object.raw("response_headers", json_object(&headers));
+            }
+            object.build()
+        }
+    }
+}
+
+fn escape_control(text: &str) -> String {
+    text.chars()
+        .map(|c| {
+            if c.is_control() {
+                format!("\\x{:02x}", c as u32)
+            } else {
+                c.to_string()
+            }
+        })
+        .collect()
+}
+
+/// Logs outlive the requests in them, so credentials never make it in. Only
+/// headers are ever logged; bodies, like /debug/echo's, stay out entirely.
+fn redact_headers(headers: &[(String, String)]) -> Headers {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let secret = [AUTHORIZATION, PROXY_AUTHORIZATION, COOKIE, SET_COOKIE]
+                .iter()
+                .any(|secret| name.eq_ignore_ascii_case(secret));
+            let value = if secret { "[redacted]" } else { value };
+            (name.clone(), value.to_string())
+        })
+        .collect()
+}
+
+/// Who sent the request and over what, believing the proxy only if told to.
+fn client_and_scheme(
+    peer_addr: SocketAddr,
+    http_request: &HttpRequest,
+    args: &Args,
+) -> (String, String) {
+    let info = if args.trust_proxy {
+        forwarded::parse(
+            http_request.header(FORWARDED),
+            http_request.header(X_FORWARDED_FOR),
+            http_request.header(X_FORWARDED_PROTO),
+        )
+    } else {
+        forwarded::ForwardedInfo::default()
+    };
+    (
+        info.client.unwrap_or_else(|| peer_addr.ip().to_string()),
+        info.proto.unwrap_or_else(|| "http".to_string()),
+    )
+}
+
+/// Once shutdown starts nothing new is served, clients are told to retry.
+fn check_running(state: &ServerState) -> Result<(), StatusLine> {
+    if state.shutdown.load(Ordering::SeqCst) {
+        Err(StatusLine::ServiceUnavailable(None, ContentType::TextPlain))
+    } else {
+        Ok(())
+    }
+}
+
+fn check_client(peer_addr: SocketAddr, args: &Args) -> Result<(), StatusLine> {
+    if acl::is_allowed(peer_addr.ip(), &args.allow_ips, &args.deny_ips) {
+        Ok(())
+    } else {
+        Err(StatusLine::Forbidden)
+    }
+}
+
+/// Header-level checks that have to pass before we touch the body.
+fn validate_request(http_request: &HttpRequest, args: &Args) -> Result<(), StatusLine> {
+    if http_request.method().is_none() || http_request.path().is_none() {
+        return Err(StatusLine::BadRequest);
+    }
+
+    // Methods are case-sensitive, so `get` is just a method we've never heard of
+    if !http_request
+        .method()
+        .is_some_and(|method| KNOWN_METHODS.contains(&method))
+    {
+        return Err(StatusLine::NotImplemented);
+    }
+
+    // Asterisk-form only makes sense for asking about the server as a whole
+    if http_request.target() == Some("*") && http_request.method() != Some(OPTIONS) {
+        return Err(StatusLine::BadRequest);
+    }
+
+    // Decoded NULs, newlines and broken UTF-8 have no business in a path and
+    // are only ever there to confuse logs or the filesystem
+    let decoded = percent_decode(http_request.path().unwrap_or_default());
+    if decoded.iter().any(u8::is_ascii_control) || std::str::from_utf8(&decoded).is_err() {
+        return Err(StatusLine::BadRequest);
+    }
+
+    if let (Some(max_uri_length), Some(target)) = (args.max_uri_length, http_request.target()) {
+        if target.len() > max_uri_length {
+            return Err(StatusLine::UriTooLong);
+        }
+    }
+
+    if let Some(transfer_encoding) = http_request.header(TRANSFER_ENCODING) {
+        // Chunked has to be the final coding, otherwise we can't find the end of the body
+        if !is_chunked(transfer_encoding) {
+            return Err(StatusLine::BadRequest);
+        }
+        // Both framing headers at once is the classic request smuggling vector
+        if http_request.header(CONTENT_LENGTH).is_some() && !args.lenient_framing {
+            return Err(StatusLine::BadRequest);
+        }
+    }
+
+    // A length we can't read, or two that disagree, leave the body's end up to
+    // whoever is asked, which is how requests get smuggled past a proxy
+    let mut content_lengths = http_request
+        .headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case(CONTENT_LENGTH))
+        .map(|(_, value)| parse_content_length(value));
+    if let Some(first) = content_lengths.next() {
+        if first.is_none() || content_lengths.any(|other| other != first) {
+            return Err(StatusLine::BadRequest);
+        }
+    }
+
+    if find_content_length(http_request) > MAX_BODY_BYTES {
+        return Err(StatusLine::PayloadTooLarge);
+    }
+
+    // Turn oversized uploads away before reading them; chunked ones are caught
+    // once read, in `handle_file_path`
+    let is_upload = matches!(http_request.method(), Some(POST | PATCH))
+        && http_request
+            .path()
+            .is_some_and(|path| path.starts_with("/files/"));
+    if is_upload
+        && args
+            .max_file_size
+            .is_some_and(|max_file_size| find_content_length(http_request) as u64 > max_file_size)
+    {
+        return Err(StatusLine::PayloadTooLarge);
+    }
+
+    // Bodies on these are legal but meaningless, and a favourite of smuggling attempts
+    let bodiless_method = matches!(http_request.method(), Some(GET | HEAD | DELETE));
+    let has_body =
+        find_content_length(http_request) > 0 || http_request.header(TRANSFER_ENCODING).is_some();
+    if args.reject_get_body && bodiless_method && has_body {
+        return Err(StatusLine::BadRequest);
+    }
+
+    if http_request.obs_folded && !args.lenient_folding {
+        return Err(StatusLine::BadRequest);
+    }
+
+    if args.require_user_agent
+        && http_request
+            .header(USER_AGENT)
+            .unwrap_or_default()
+            .is_empty()
+    {
+        return Err(StatusLine::BadRequest);
+    }
+
+    Ok(())
+}
+
+/// Why a body couldn't be read: either the client got the framing wrong and
+/// deserves an answer, or the connection itself failed.
+enum BodyError {
+    Malformed(StatusLine),
+    Io(std::io::Error),
+}
+
+impl From<std::io::Error> for BodyError {
+    fn from(error: std::io::Error) -> Self {
+        BodyError::Io(error)
+    }
+}
+
+fn process_request_body(
+    buf_reader: &mut BufReader<TcpStream>,
+    http_request: &mut HttpRequest,
+    args: &Args,
+) -> Result<(), BodyError> {
+    let is_upload = http_request
+        .path()
+        .is_some_and(|path| path.starts_with("/files/"));
+    let interval = if is_upload {
+        args.upload_progress_bytes
+    } else {
+        0
+    };
+    let target = http_request.path().unwrap_or_default().to_string();
+
+    // Transfer-Encoding always wins over Content-Length (RFC 7230 3.3.3)
+    let buffer = if http_request.header(TRANSFER_ENCODING).is_some() {
+        let mut progress = UploadProgress::new(target, None, interval);
+        let (buffer, trailers) = read_chunked_body(buf_reader, &mut progress)?;
+        http_request.trailers = trailers;
+        buffer
+    } else {
+        let content_length = find_content_length(http_request);
+        let mut progress = UploadProgress::new(target, Some(content_length), interval);
+        // Grown as bytes actually arrive, a claimed length alone costs nothing
+        let mut buffer = Vec::new();
+        while buffer.len() < content_length {
+            let block = (content_length - buffer.len()).min(64 * 1024);
+            let read = buf_reader
+                .by_ref()
+                .take(block as u64)
+                .read_to_end(&mut buffer)?;
+            if read == 0 {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+            progress.advance(read);
+        }
+        buffer
+    };
+    http_request.body = buffer;
+    Ok(())
+}
+
+/// Decodes a chunked body, returning it along with any trailer headers.
+fn read_chunked_body(
+    buf_reader: &mut BufReader<TcpStream>,
+    progress: &mut UploadProgress,
+) -> Result<(Vec<u8>, Headers), BodyError> {
+    let mut body = Vec::new();
+    loop {
+        let mut size_line = String::new();
+        if buf_reader
+            .by_ref()
+            .take(MAX_CHUNK_LINE)
+            .read_line(&mut size_line)?
+            == 0
+        {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        if !size_line.ends_with('\n') {
+            return Err(BodyError::Malformed(StatusLine::BadRequest));
+        }
+        // Chunk extensions (";name=value") are allowed but meaningless to us
+        let size = size_line.split(';').next().unwrap_or_default().trim();
+        // from_str_radix would also take a sign, which isn't a hex digit
+        if size.is_empty() || !size.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(BodyError::Malformed(StatusLine::BadRequest));
+        }
+        let size = usize::from_str_radix(size, 16)
+            .ok()
+            .filter(|&size| {
+                body.len()
+                    .checked_add(size)
+                    .is_some_and(|total| total <= MAX_BODY_BYTES)
+            })
+            .ok_or(BodyError::Malformed(StatusLine::PayloadTooLarge))?;
+
+        if size == 0 {
+            let mut trailers = Vec::new();
+            loop {
+                let mut line = String::new();
+                if buf_reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    trailers.push((name.trim().to_string(), value.trim().to_string()));
+                }
+            }
+            return Ok((body, trailers));
+        }
+
+        let read = buf_reader
+            .by_ref()
+            .take(size as u64)
+            .read_to_end(&mut body)?;
+        if read < size {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        let mut crlf = [0; 2];
+        buf_reader.read_exact(&mut crlf)?;
+        if &crlf != b"\r\n" {
+            return Err(BodyError::Malformed(StatusLine::BadRequest));
+        }
+        progress.advance(size);
+    }
+}
+
+/// Reports a long upload each time another `interval` bytes have come in, so
+/// operators can tell a slow transfer from a stuck one. An interval of 0 is silent.
+struct UploadProgress {
+    target: String,
+    total: Option<usize>,
+    interval: usize,
+    received: usize,
+}
+
+impl UploadProgress {
+    fn new(target: String, total: Option<usize>, interval: usize) -> UploadProgress {
+        UploadProgress {
+            target,
+            total,
+            interval,
+            received: 0,
+        }
+    }
+
+    fn advance(&mut self, bytes: usize) {
+        let before = self.received;
+        self.received += bytes;
+        if self.interval == 0 || before / self.interval == self.received / self.interval {
+            return;
+        }
+        match self.total {
+            Some(total) => println!("upload {}: {}/{} bytes", self.target, self.received, total),
+            None => println!("upload {}: {} bytes", self.target, self.received),
+        }
+    }
+}
+
+fn generate_response(
+    http_request: &HttpRequest,
+    role: ListenerRole,
+    state: &ServerState,
+) -> Result<HttpResponse, Box<dyn Error>> {
+    let args = &state.args;
+    if !http_request.path().is_some_and(|path| role.serves(path)) {
+        return Ok(StatusLine::NotFound.into());
+    }
+    if !args.vhosts.is_empty() && served_directory(http_request, args).is_none() {
+        return Ok(match args.vhost_no_match {
+            VhostNoMatch::NotFound => StatusLine::NotFound.into(),
+            VhostNoMatch::MisdirectedRequest => StatusLine::MisdirectedRequest.into(),
+        });
+    }
+
+    match http_request.path() {
+        Some("*") => Ok(allow_response(SERVER_METHODS)),
+        Some(path) if !path_allowed(path, &state.args.allow_paths) => {
+            Ok(StatusLine::NotFound.into())
+        }
+        Some(path) if http_request.method() == Some(OPTIONS) => {
+            Ok(match route_methods(path, state) {
+                Some(methods) => allow_response(methods),
+                None if state.args.options_404_on_unknown => StatusLine::NotFound.into(),
+                None => allow_response(SERVER_METHODS),
+            })
+        }
+        Some("/") => Ok(StatusLine::Ok(None, ContentType::TextPlain).into()),
+        Some(path) => Ok(path_to_status_line(path, http_request, state)),
+        _ => Ok(StatusLine::NotFound.into()),
+    }
+}
+
+/// Every method some route on this server answers.
+const SERVER_METHODS: &[&str] = &[GET, HEAD, POST, PATCH];
+
+fn allow_response(methods: &[&str]) -> HttpResponse {
+    let allow = std::iter::once(OPTIONS)
+        .chain(methods.iter().copied())
+        .collect::<Vec<_>>()
+        .join(", ");
+    HttpResponse::from(StatusLine::NoContent).with_header(ALLOW, allow)
+}
+
+/// The methods the route serving `path` answers, or `None` if nothing does.
+/// Has to be kept in step with `path_to_status_line`.
+fn route_methods(path: &str, state: &ServerState) -> Option<&'static [&'static str]> {
+    let args = &state.args;
+    match path {
+        "/" | "/health" | "/favicon.ico" | "/user-agent" => Some(&[GET, HEAD]),
+        "/echo" => Some(&[POST]),
+        _ if path.starts_with("/admin/") && args.admin_token.is_some() => Some(&[GET, POST]),
+        _ if path.starts_with("/debug/") && args.enable_debug_routes => Some(&[GET, HEAD, POST]),
+        _ if path.starts_with("/echo/") => Some(&[GET, HEAD]),
+        _ if path.starts_with("/files/")
+            && (args.directory.is_some() || !args.vhosts.is_empty()) =>
+        {
+            Some(&[GET, HEAD, POST, PATCH])
+        }
+        _ => None,
+    }
+}
+
+/// Prefixes match whole segments, so `/files` covers `/files/a` but not `/filesystem`.
+fn path_allowed(path: &str, allow_paths: &[String]) -> bool {
+    allow_paths.is_empty()
+        || allow_paths.iter().any(|prefix| {
+            path.strip_prefix(prefix.as_str()).is_some_and(|rest| {
+                rest.is_empty() || prefix.ends_with('/') || rest.starts_with('/')
+            })
+        })
+}
+
+/// Adds the headers that depend on server configuration rather than on the route.
+fn finalize_response(response: HttpResponse, state: &ServerState) -> HttpResponse {
+    let args = &state.args;
+    // Called exactly once per request, which makes it the natural place to count them
+    let request_count = state.requests_served.fetch_add(1, Ordering::Relaxed) + 1;
+    let response = if args.enable_request_count_header {
+        response.with_header("X-Request-Count", request_count.to_string())
+    } else {
+        response
+    };
+    let mut response = response.with_header("X-Served-By", state.instance_id.as_str());
+    if let Some(policy) = &args.csp {
+        let is_html = matches!(
+            response.status_line.content_type(),
+            Some(ContentType::TextHtml)
+        );
+        if is_html || args.csp_all_responses {
+            let name = if args.csp_report_only {
+                "Content-Security-Policy-Report-Only"
+            } else {
+                "Content-Security-Policy"
+            };
+            response = response.with_header(name, policy.as_str());
+        }
+    }
+    for (name, value) in &state.default_headers {
+        let overridden = response
+            .headers
+            .iter()
+            .any(|(existing, _)| existing.eq_ignore_ascii_case(name));
+        if !overridden {
+            response = response.with_header(name, value.as_str());
+        }
+    }
+
+    match response.status_line {
+        StatusLine::ServiceUnavailable(..) => {
+            let retry_after = match args.retry_after_format {
+                RetryAfterFormat::Seconds => args.retry_after_secs.to_string(),
+                RetryAfterFormat::Date => {
+                    http_date(SystemTime::now() + Duration::from_secs(args.retry_after_secs))
+                }
+            };
+            let status_line = match &state.busy_page {
+                Some(page) => {
+                    StatusLine::ServiceUnavailable(Some(page.clone()), ContentType::TextHtml)
+                }
+                None => StatusLine::ServiceUnavailable(
+                    Some("Service Unavailable, please try again later".into()),
+                    ContentType::TextPlain,
+                ),
+            };
+            HttpResponse {
+                status_line,
+                ..response
+            }
+            .with_header(RETRY_AFTER, retry_after)
+        }
+        _ => response,
+    }
+}
+
+fn send_response(
+    buf_writer: &mut BufWriter<TcpStream>,
+    response: &HttpResponse,
+    include_body: bool,
+) -> Result<usize, Box<dyn Error>> {
+    let written = response.write_to(buf_writer, include_body)?;
+    buf_writer.flush()?;
+    Ok(written)
+}
+
+/// How often a followed file is checked for appended bytes.
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Streams a file as chunks, then keeps polling for appends like `tail -f`
+/// until `--tail-max-secs` or `--tail-max-bytes` runs out.
+fn follow_file(
+    buf_writer: &mut BufWriter<TcpStream>,
+    path: &Path,
+    args: &Args,
+) -> Result<(), Box<dyn Error>> {
+    let mut file = fs::File::open(path)?;
+    let deadline = Instant::now() + Duration::from_secs(args.tail_max_secs);
+    let mut remaining = args.tail_max_bytes;
+    let mut buffer = vec![0; 64 * 1024];
+
+    while remaining > 0 && Instant::now() < deadline {
+        let limit = buffer
+            .len()
+            .min(usize::try_from(remaining).unwrap_or(usize::MAX));
+        let read = file.read(&mut buffer[..limit])?;
+        if read == 0 {
+            thread::sleep(FOLLOW_POLL_INTERVAL);
+            continue;
+        }
+        write!(buf_writer, "{:x}\r\n", read)?;
+        buf_writer.write_all(&buffer[..read])?;
+        buf_writer.write_all(b"\r\n")?;
+        buf_writer.flush()?;
+        remaining -= read as u64;
+    }
+
+    buf_writer.write_all(b"0\r\n\r\n")?;
+    buf_writer.flush()?;
+    Ok(())
+}
+
+fn path_to_status_line(
+    path: &str,
+    http_request: &HttpRequest,
+    state: &ServerState,
+) -> HttpResponse {
+    if path == "/health" {
+        return handle_health(state).into();
+    }
+
+    if let Some(admin_path) = path.strip_prefix("/admin/") {
+        return handle_admin_path(admin_path, http_request, state);
+    }
+
+    if let Some(debug_path) = path.strip_prefix("/debug/") {
+        if state.args.enable_debug_routes {
+            return handle_debug_path(debug_path, http_request);
+        }
+        return StatusLine::NotFound.into();
+    }
+
+    if path == "/echo" && http_request.method() == Some(POST) {
+        return handle_echo_body(http_request);
+    }
+
+    if path == "/favicon.ico" && matches!(http_request.method(), Some(GET | HEAD)) {
+        return handle_favicon(http_request, state);
+    }
+
+    if let Some(s) = path.strip_prefix("/echo/") {
+        return handle_echo(s, http_request);
+    }
+
+    if let Some(file_path) = path.strip_prefix("/files/") {
+        return handle_file_path(file_path, http_request, state);
+    }
+
+    if path == "/user-agent" {
+        return handle_user_agent(http_request).into();
+    }
+
+    StatusLine::NotFound.into()
+}
+
+fn handle_file_path(
+    file_path: &str,
+    http_request: &HttpRequest,
+    state: &ServerState,
+) -> HttpResponse {
+    let args = &state.args;
+    // Listing links are percent-encoded, and `%2e%2e` is as much a way out as
+    // `..`; `validate_request` has already refused anything that isn't UTF-8
+    let decoded = String::from_utf8_lossy(&percent_decode(file_path)).into_owned();
+    let file_path = decoded.as_str();
+    if escapes_directory(file_path) {
+        return match args.traversal_response {
+            TraversalResponse::NotFound => StatusLine::NotFound.into(),
+            TraversalResponse::Forbidden => StatusLine::Forbidden.into(),
+        };
+    }
+    let full_path = match served_directory(http_request, args) {
+        Some(directory) => directory.join(file_path),
+        None => return StatusLine::NotFound.into(),
+    };
+
+    // Not every client replays a body at the new location, so only reads move
+    let is_read = matches!(http_request.method(), Some(GET | HEAD));
+    if args.trailing_slash_redirect && is_read && !file_path.ends_with('/') && full_path.is_dir() {
+        let mut location = format!("{}/", http_request.path().unwrap_or_default());
+        if let Some(query) = http_request.query() {
+            location.push('?');
+            location.push_str(query);
+        }
+        return HttpResponse::from(StatusLine::MovedPermanently).with_header(LOCATION, location);
+    }
+
+    let lists_directory = args.enable_listing && is_read;
+    if lists_directory && full_path.is_dir() {
+        return handle_listing(&full_path, http_request, args);
+    }
+
+    // FIFOs and devices can block a read forever or never reach EOF
+    if fs::metadata(&full_path).is_ok_and(|metadata| !metadata.is_file()) {
+        return StatusLine::NotFound.into();
+    }
+
+    if let Some(max_file_size) = args.max_file_size {
+        let size = fs::metadata(&full_path).map_or(0, |metadata| metadata.len());
+        let body = http_request.body.len() as u64;
+        match http_request.method() {
+            Some(GET | HEAD) if size > max_file_size => return StatusLine::Forbidden.into(),
+            // POST replaces the file while PATCH adds to it
+            Some(POST) if body > max_file_size => return StatusLine::PayloadTooLarge.into(),
+            Some(PATCH) if size + body > max_file_size => {
+                return StatusLine::PayloadTooLarge.into()
+            }
+            _ => {}
+        }
+    }
+
+    match http_request.method() {
+        Some(GET) if args.enable_tail && http_request.query_param("follow") == Some("1") => {
+            if !full_path.is_file() {
+                return StatusLine::NotFound.into();
+            }
+            let mut response: HttpResponse =
+                StatusLine::Ok(None, content_type_for_path(&full_path)).into();
+            response.follow = Some(full_path);
+            response.with_header(TRANSFER_ENCODING, "chunked")
+        }
+        Some(GET) => {
+            let sibling = precompressed_sibling(&full_path, args);
+            let negotiated = sibling.as_deref().filter(|_| accepts_gzip(http_request));
+            match read_file(negotiated.unwrap_or(&full_path), state) {
+                Ok((file_contents, cached_for)) => {
+                    let response: HttpResponse =
+                        StatusLine::Ok(Some(file_contents), content_type_for_path(&full_path))
+                            .into();
+                    let response = match cached_for {
+                        Some(age) => response.with_header("Age", age.as_secs().to_string()),
+                        None => response,
+                    };
+                    let response = with_file_headers(response, &full_path, args);
+                    match sibling {
+                        Some(_) => {
+                            with_encoding_headers(response, negotiated.is_some(), http_request)
+                        }
+                        None => response,
+                    }
+                }
+                Err(_) => StatusLine::NotFound.into(),
+            }
+        }
+        Some(HEAD) => handle_file_head(&full_path, http_request, args),
+        Some(POST | PATCH) if !body_intact(http_request) => StatusLine::BadRequest.into(),
+        Some(POST) if args.post_create_only => {
+            // create_new so two racing creates can't both succeed
+            let created = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&full_path)
+                .and_then(|mut file| file.write_all(&http_request.body));
+            match created {
+                Ok(()) => StatusLine::Created(ContentType::TextPlain).into(),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    StatusLine::Conflict.into()
+                }
+                Err(_) => StatusLine::NotFound.into(),
+            }
+        }
+        Some(POST) => {
+            let _guard = match state.upload_locks.lock(&full_path) {
+                Some(guard) => guard,
+                None => return StatusLine::ServiceUnavailable(None, ContentType::TextPlain).into(),
+            };
+            let written = fs::write(&full_path, &http_request.body);
+            if let Some(file_cache) = &state.file_cache {
+                file_cache.invalidate(&full_path);
+            }
+            match written {
+                Ok(()) => StatusLine::Created(ContentType::TextPlain).into(),
+                Err(e) => {
+                    eprintln!("error: writing {}: {}", full_path.display(), e);
+                    write_error_status(&e).into()
+                }
+            }
+        }
+        Some(PATCH) => handle_file_patch(&full_path, http_request, state),
+        _ => StatusLine::NotFound.into(),
+    }
+}
+
+/// A missing parent directory is the client's mistake; anything else
+/// (permissions, a full disk) is ours.
+fn write_error_status(e: &std::io::Error) -> StatusLine {
+    match e.kind() {
+        std::io::ErrorKind::NotFound => StatusLine::NotFound,
+        _ => StatusLine::InternalServerError,
+    }
+}
+
+struct ListingEntry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    /// Seconds since the epoch, if the platform reports it
+    modified: Option<u64>,
+}
+
+/// An index of a directory, HTML by default or JSON with `?format=json`.
+/// Only the first `--listing-max-entries` names, in order, are listed.
+fn handle_listing(directory: &Path, http_request: &HttpRequest, args: &Args) -> HttpResponse {
+    let as_json = match http_request.query_param("format") {
+        None | Some("html") => false,
+        Some("json") => true,
+        Some(_) => return StatusLine::BadRequest.into(),
+    };
+    let mut entries: Vec<ListingEntry> = match fs::read_dir(directory) {
+        Ok(entries) => entries
+            .flatten()
+            .map(|entry| {
+                let metadata = entry.metadata().ok();
+                ListingEntry {
+                    name: entry.file_name().to_string_lossy().into_owned(),
+                    is_dir: metadata.as_ref().is_some_and(|metadata| metadata.is_dir()),
+                    size: metadata.as_ref().map_or(0, |metadata| metadata.len()),
+                    modified: metadata
+                        .and_then(|metadata| metadata.modified().ok())
+                        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                        .map(|modified| modified.as_secs()),
+                }
+            })
+            .collect(),
+        Err(_) => return StatusLine::NotFound.into(),
+    };
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    entries.truncate(args.listing_max_entries);
+
+    if as_json {
+        let body = json_array(entries.iter().map(|entry| {
+            JsonObject::new()
+                .string("name", &entry.name)
+                .raw("size", entry.size)
+                .raw("is_dir", entry.is_dir)
+                .raw("mtime", json_optional(entry.modified))
+                .build()
+        }));
+        return StatusLine::Ok(Some(body.into_bytes()), ContentType::ApplicationJson).into();
+    }
+    html_listing(&entries, http_request, args)
+}
+
+/// Links are absolute so they work whether or not the request path ended in a slash.
+fn html_listing(entries: &[ListingEntry], http_request: &HttpRequest, args: &Args) -> HttpResponse {
+    let base = http_request
+        .path()
+        .unwrap_or_default()
+        .trim_end_matches('/');
+    let title = html_escape(&format!("Index of {}/", base));
+    let mut body = format!(
+        "<!DOCTYPE html>\n<html><head><title>{0}</title></head><body><h1>{0}</h1><ul>\n",
+        title
+    );
+    for entry in entries {
+        let slash = if entry.is_dir { "/" } else { "" };
+        body.push_str(&format!(
+            "<li><a href=\"{}/{}{}\">{}{}</a></li>\n",
+            html_escape(base),
+            html_escape(&percent_encode(&entry.name)),
+            slash,
+            html_escape(&truncate_name(&entry.name, args.listing_name_maxlen)),
+            slash
+        ));
+    }
+    body.push_str("</ul></body></html>\n");
+
+    StatusLine::Ok(Some(body.into_bytes()), ContentType::TextHtml).into()
+}
+
+/// Cuts a name down to `max_len` characters, the last being an ellipsis.
+fn truncate_name(name: &str, max_len: usize) -> String {
+    if name.chars().count() <= max_len {
+        return name.to_string();
+    }
+    let mut truncated: String = name.chars().take(max_len.saturating_sub(1)).collect();
+    truncated.push('…');
+    truncated
+}
+
+fn html_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Undoes `%XX` escapes, leaving malformed ones as they are.
+fn percent_decode(text: &str) -> Vec<u8> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|hex| bytes[i] == b'%' && hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    decoded
+}
+
+/// Escapes everything but RFC 3986 unreserved characters, for one path segment.
+fn percent_encode(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// The directory files are served from for this request: the matching vhost's,
+/// or `--directory` when no vhost claims the Host.
+fn served_directory<'a>(http_request: &HttpRequest, args: &'a Args) -> Option<&'a Path> {
+    let host = http_request.header("Host").map(|host| {
+        // Drop any port, minding the colons inside a bracketed IPv6 address
+        match host.rfind(':') {
+            Some(colon) if !host[colon..].contains(']') => &host[..colon],
+            _ => host,
+        }
+    });
+    host.and_then(|host| {
+        args.vhosts
+            .iter()
+            .find(|vhost| vhost.host.eq_ignore_ascii_case(host))
+    })
+    .map(|vhost| vhost.directory.as_path())
+    .or(args.directory.as_deref())
+}
+
+fn body_intact(http_request: &HttpRequest) -> bool {
+    digest::verify(
+        &http_request.body,
+        http_request.header(CONTENT_MD5),
+        http_request.header(DIGEST),
+    )
+}
+
+/// The headers a GET would send, worked out from metadata alone so a HEAD
+/// on a large file never reads it. Upload-Offset lets a resuming client see
+/// how much of the file we already hold.
+fn handle_file_head(full_path: &Path, http_request: &HttpRequest, args: &Args) -> HttpResponse {
+    let metadata = match fs::metadata(full_path) {
+        Ok(metadata) if metadata.is_file() => metadata,
+        _ => return StatusLine::NotFound.into(),
+    };
+    let sibling = precompressed_sibling(full_path, args);
+    let negotiated = sibling
+        .as_deref()
+        .filter(|_| accepts_gzip(http_request))
+        .and_then(|sibling| fs::metadata(sibling).ok());
+    // Upload-Offset is about the file itself, whichever representation we'd send
+    let length = negotiated.as_ref().unwrap_or(&metadata).len();
+    let response = HttpResponse::from(StatusLine::Ok(None, content_type_for_path(full_path)))
+        .with_header(CONTENT_LENGTH, length.to_string())
+        .with_header(UPLOAD_OFFSET, metadata.len().to_string());
+    let response = with_file_headers(response, full_path, args);
+    match sibling {
+        Some(_) => with_encoding_headers(response, negotiated.is_some(), http_request),
+        None => response,
+    }
+}
+
+/// A `name.gz` next to the requested file, when `--precompressed` says to look.
+fn precompressed_sibling(full_path: &Path, args: &Args) -> Option<PathBuf> {
+    if !args.precompressed {
+        return None;
+    }
+    let mut sibling = full_path.as_os_str().to_owned();
+    sibling.push(".gz");
+    Some(PathBuf::from(sibling)).filter(|sibling| sibling.is_file())
+}
+
+fn accepts_gzip(http_request: &HttpRequest) -> bool {
+    let accept_encoding = http_request.header(ACCEPT_ENCODING).unwrap_or_default();
+    accept_encoding.split(',').any(|coding| {
+        let mut params = coding.split(';').map(str::trim);
+        let name = params.next().unwrap_or_default();
+        // An explicit q=0 means "anything but this"
+        let refused = params
+            .filter_map(|param| param.strip_prefix("q="))
+            .any(|q| q.parse::<f32>().is_ok_and(|q| q == 0.0));
+        (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+    })
+}
+
+/// Marks which representation went out when a file has a gzip sibling. Vary
+/// goes on either way so caches don't hand one client's choice to another.
+fn with_encoding_headers(
+    response: HttpResponse,
+    negotiated: bool,
+    http_request: &HttpRequest,
+) -> HttpResponse {
+    let response = response.with_header("Vary", ACCEPT_ENCODING);
+    if !negotiated {
+        return response;
+    }
+    let location = format!("{}.gz", http_request.path().unwrap_or_default());
+    response
+        .with_header("Content-Encoding", "gzip")
+        .with_header("Content-Location", location)
+}
+
+/// The per-extension headers GET and HEAD on a file share.
+fn with_file_headers(response: HttpResponse, full_path: &Path, args: &Args) -> HttpResponse {
+    let mut response = match cache_max_age(full_path, &args.cache_rules) {
+        Some(max_age) => response.with_header(CACHE_CONTROL, format!("max-age={}", max_age)),
+        None => response,
+    };
+    for asset in preload_assets(full_path, &args.preload_rules) {
+        response = response.with_header(LINK, preload_link(asset));
+    }
+    response
+}
+
+/// Appends a piece of a resumable upload. The client states where it thinks
+/// the file ends and we refuse with 409 if that's not where it actually ends,
+/// so a retried or reordered piece can never be written twice.
+fn handle_file_patch(
+    full_path: &Path,
+    http_request: &HttpRequest,
+    state: &ServerState,
+) -> HttpResponse {
+    let offset: u64 = match http_request
+        .header(UPLOAD_OFFSET)
+        .and_then(|offset| offset.parse().ok())
+    {
+        Some(offset) => offset,
+        None => return StatusLine::BadRequest.into(),
+    };
+
+    // Another piece landing between the check and the append would put this
+    // one at the wrong offset, so both happen under the path's lock
+    let _guard = match state.upload_locks.lock(full_path) {
+        Some(guard) => guard,
+        None => return StatusLine::ServiceUnavailable(None, ContentType::TextPlain).into(),
+    };
+    // Only a first piece may create the file, a late one shouldn't leave it empty
+    let appended = fs::OpenOptions::new()
+        .create(offset == 0)
+        .append(true)
+        .open(full_path)
+        .and_then(|mut file| {
+            let current = file.metadata()?.len();
+            if offset == current {
+                file.write_all(&http_request.body)?;
+            }
+            Ok(current)
+        });
+    let current = match appended {
+        Ok(current) => current,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && offset != 0 => 0,
+        Err(e) => {
+            eprintln!("error: appending to {}: {}", full_path.display(), e);
+            return write_error_status(&e).into();
+        }
+    };
+    if offset != current {
+        return HttpResponse::from(StatusLine::Conflict)
+            .with_header(UPLOAD_OFFSET, current.to_string());
+    }
+    if let Some(file_cache) = &state.file_cache {
+        file_cache.invalidate(full_path);
+    }
+    HttpResponse::from(StatusLine::NoContent).with_header(
+        UPLOAD_OFFSET,
+        (current + http_request.body.len() as u64).to_string(),
+    )
+}
+
+/// Whether a requested path could resolve outside the served directory, via
+/// `..` or by being absolute (joining an absolute path replaces the base).
+fn escapes_directory(file_path: &str) -> bool {
+    Path::new(file_path)
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+/// Reads a served file, through the cache if there is one, with the age of
+/// the cached copy when that's where it came from.
+fn read_file(path: &Path, state: &ServerState) -> std::io::Result<(Vec<u8>, Option<Duration>)> {
+    match &state.file_cache {
+        Some(file_cache) => file_cache.read(path),
+        None => Ok((fs::read(path)?, None)),
+    }
+}
+
+/// Guesses a served file's type from its extension, falling back to raw bytes.
+fn content_type_for_path(path: &Path) -> ContentType {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("txt") => ContentType::TextPlain,
+        Some("html" | "htm") => ContentType::TextHtml,
+        Some("css") => ContentType::TextCss,
+        Some("js" | "mjs") => ContentType::ApplicationJavascript,
+        Some("json") => ContentType::ApplicationJson,
+        // Browsers refuse to stream-compile modules served as anything else
+        Some("wasm") => ContentType::ApplicationWasm,
+        Some("png") => ContentType::ImagePng,
+        Some("jpg" | "jpeg") => ContentType::ImageJpeg,
+        Some("gif") => ContentType::ImageGif,
+        Some("webp") => ContentType::ImageWebp,
+        Some("avif") => ContentType::ImageAvif,
+        Some("svg") => ContentType::ImageSvg,
+        Some("ico") => ContentType::ImageXIcon,
+        Some("woff2") => ContentType::FontWoff2,
+        _ => ContentType::ApplicationOctetStream,
+    }
+}
+
+fn preload_assets<'a>(path: &Path, preload_rules: &'a [PreloadRule]) -> &'a [String] {
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    extension
+        .and_then(|extension| {
+            preload_rules
+                .iter()
+                .find(|rule| rule.extension.eq_ignore_ascii_case(extension))
+        })
+        .map_or(&[], |rule| &rule.assets)
+}
+
+/// A preload hint, with `as` guessed from the asset's extension since
+/// browsers ignore preloads that don't say what they're for.
+fn preload_link(asset: &str) -> String {
+    let extension = Path::new(asset)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let destination = match extension.as_str() {
+        "css" => "style",
+        "js" | "mjs" => "script",
+        // Fonts are always fetched in CORS mode, the preload has to match
+        "woff" | "woff2" | "ttf" | "otf" => {
+            return format!("<{}>; rel=preload; as=font; crossorigin", asset)
+        }
+        "png" | "jpg" | "jpeg" | "gif" | "webp" | "avif" | "svg" | "ico" => "image",
+        _ => "fetch",
+    };
+    format!("<{}>; rel=preload; as={}", asset, destination)
+}
+
+fn cache_max_age(path: &Path, cache_rules: &[CacheRule]) -> Option<u64> {
+    let extension = path.extension()?.to_str()?;
+    cache_rules
+        .iter()
+        .find(|rule| rule.extension.eq_ignore_ascii_case(extension))
+        .map(|rule| rule.max_age)
+}
+
+fn handle_health(state: &ServerState) -> StatusLine {
+    if state.ready.load(Ordering::SeqCst) {
+        StatusLine::Ok(Some("OK".into()), ContentType::TextPlain)
+    } else {
+        StatusLine::ServiceUnavailable(None, ContentType::TextPlain)
+    }
+}
+
+fn handle_admin_path(
+    admin_path: &str,
+    http_request: &HttpRequest,
+    state: &ServerState,
+) -> HttpResponse {
+    let token = match &state.args.admin_token {
+        Some(token) => token,
+        None => return StatusLine::NotFound.into(),
+    };
+
+    let presented = http_request
+        .header(AUTHORIZATION)
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if presented != Some(token.as_str()) {
+        return HttpResponse::from(StatusLine::Unauthorized)
+            .with_header("WWW-Authenticate", "Bearer");
+    }
+
+    match (http_request.method(), admin_path) {
+        (Some(POST), "drain") => {
+            state.ready.store(false, Ordering::SeqCst);
+            StatusLine::Ok(Some("draining".into()), ContentType::TextPlain).into()
+        }
+        (Some(POST), "warmup") => handle_warmup(state),
+        (Some(GET), "file-cache") => match &state.file_cache {
+            Some(file_cache) => {
+                let stats = file_cache.stats();
+                let body = JsonObject::new()
+                    .raw("entries", stats.entries)
+                    .raw("bytes", stats.bytes)
+                    .raw("hits", stats.hits)
+                    .raw("misses", stats.misses)
+                    .build();
+                StatusLine::Ok(Some(body.into()), ContentType::ApplicationJson).into()
+            }
+            None => StatusLine::NotFound.into(),
+        },
+        (Some(GET), "config") => StatusLine::Ok(
+            Some(config_json(state).into()),
+            ContentType::ApplicationJson,
+        )
+        .into(),
+        _ => StatusLine::NotFound.into(),
+    }
+}
+
+/// Pulls every regular file under the served directory into the file cache,
+/// stopping short of the cache's size limit.
+fn handle_warmup(state: &ServerState) -> HttpResponse {
+    let (file_cache, directory) = match (&state.file_cache, &state.args.directory) {
+        (Some(file_cache), Some(directory)) => (file_cache, directory),
+        _ => return StatusLine::NotFound.into(),
+    };
+
+    let mut files = Vec::new();
+    if let Err(e) = collect_files(directory, &mut files) {
+        eprintln!("warmup: could not walk {}: {}", directory.display(), e);
+    }
+
+    let (mut cached_files, mut cached_bytes, mut skipped) = (0, 0, 0);
+    for file in &files {
+        match file_cache.preload(file) {
+            Ok(Some(bytes)) => {
+                cached_files += 1;
+                cached_bytes += bytes;
+            }
+            // Too big for what's left of the cache, or unreadable
+            Ok(None) | Err(_) => skipped += 1,
+        }
+    }
+
+    let body = JsonObject::new()
+        .raw("files", cached_files)
+        .raw("bytes", cached_bytes)
+        .raw("skipped", skipped)
+        .build();
+    StatusLine::Ok(Some(body.into()), ContentType::ApplicationJson).into()
+}
+
+fn collect_files(directory: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            collect_files(&entry.path(), files)?;
+        } else if file_type.is_file() {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// The effective configuration, with secrets replaced by a marker.
+fn config_json(state: &ServerState) -> String {
+    let args = &state.args;
+    let redacted =
+        |secret: &Option<String>| json_optional(secret.as_ref().map(|_| "\"[redacted]\""));
+    let path = |path: &Option<PathBuf>| {
+        json_optional(
+            path.as_ref()
+                .map(|path| json_string(&path.to_string_lossy())),
+        )
+    };
+    let value_name = |value: &dyn ValueName| json_string(&value.value_name());
+    let ranges =
+        |ranges: &[IpNet]| json_array(ranges.iter().map(|net| json_string(&net.to_string())));
+    let cache_rules = args
+        .cache_rules
+        .iter()
+        .fold(JsonObject::new(), |object, rule| {
+            object.raw(&rule.extension, rule.max_age)
+        })
+        .build();
+    let preload_rules = args
+        .preload_rules
+        .iter()
+        .fold(JsonObject::new(), |object, rule| {
+            object.raw(
+                &rule.extension,
+                json_array(rule.assets.iter().map(|asset| json_string(asset))),
+            )
+        })
+        .build();
+    let vhosts = args
+        .vhosts
+        .iter()
+        .fold(JsonObject::new(), |object, vhost| {
+            object.string(&vhost.host, &vhost.directory.to_string_lossy())
+        })
+        .build();
+
+    JsonObject::new()
+        .raw("directory", path(&args.directory))
+        .raw("pid_file", path(&args.pid_file))
+        .raw("pid_file_overwrite", args.pid_file_overwrite)
+        .raw("shutdown_grace_secs", args.shutdown_grace_secs)
+        .raw("lenient_framing", args.lenient_framing)
+        .raw("admin_token", redacted(&args.admin_token))
+        .raw("cache_rules", cache_rules)
+        .raw("require_user_agent", args.require_user_agent)
+        .raw("concurrency_model", value_name(&args.concurrency_model))
+        .raw("threads", json_optional(args.threads))
+        .raw("min_threads", json_optional(args.min_threads))
+        .raw("max_threads", json_optional(args.max_threads))
+        .raw("enable_debug_routes", args.enable_debug_routes)
+        .raw("file_cache", args.file_cache)
+        .raw(
+            "file_cache_ttl_secs",
+            json_optional(args.file_cache_ttl_secs),
+        )
+        .raw(
+            "file_cache_max_bytes",
+            json_optional(args.file_cache_max_bytes),
+        )
+        .raw("retry_after_secs", args.retry_after_secs)
+        .raw("retry_after_format", value_name(&args.retry_after_format))
+        .raw("allow_ips", ranges(&args.allow_ips))
+        .raw("deny_ips", ranges(&args.deny_ips))
+        .raw("lenient_folding", args.lenient_folding)
+        .raw("traversal_response", value_name(&args.traversal_response))
+        .raw("max_uri_length", json_optional(args.max_uri_length))
+        .raw("lenient_methods", args.lenient_methods)
+        .raw("keep_alive_timeout_secs", args.keep_alive_timeout_secs)
+        .raw("keep_alive_max", args.keep_alive_max)
+        .raw("bind_retries", args.bind_retries)
+        .raw("bind_retry_delay_ms", args.bind_retry_delay_ms)
+        .raw("trailing_slash_redirect", args.trailing_slash_redirect)
+        .raw(
+            "enable_request_count_header",
+            args.enable_request_count_header,
+        )
+        .raw(
+            "allow_paths",
+            json_array(args.allow_paths.iter().map(|prefix| json_string(prefix))),
+        )
+        .raw("trust_proxy", args.trust_proxy)
+        .raw("options_404_on_unknown", args.options_404_on_unknown)
+        .raw("log_format", value_name(&args.log_format))
+        .raw("log_headers", args.log_headers)
+        .raw("log_response_headers", args.log_response_headers)
+        .raw("busy_page", path(&args.busy_page))
+        .raw("upload_progress_bytes", args.upload_progress_bytes)
+        .raw("post_create_only", args.post_create_only)
+        .raw("enable_tail", args.enable_tail)
+        .raw("tail_max_secs", args.tail_max_secs)
+        .raw("tail_max_bytes", args.tail_max_bytes)
+        .raw("enable_listing", args.enable_listing)
+        .raw("listing_name_maxlen", args.listing_name_maxlen)
+        .raw("listing_max_entries", args.listing_max_entries)
+        .raw("reject_get_body", args.reject_get_body)
+        .raw(
+            "instance_id",
+            json_optional(args.instance_id.as_deref().map(json_string)),
+        )
+        .raw("vhosts", vhosts)
+        .raw("vhost_no_match", value_name(&args.vhost_no_match))
+        .raw("max_file_size", json_optional(args.max_file_size))
+        .raw("preload_rules", preload_rules)
+        .raw(
+            "listen",
+            json_array(
+                args.listen
+                    .iter()
+                    .map(|listen| json_string(&listen.to_string())),
+            ),
+        )
+        .raw(
+            "bound",
+            json_array(
+                state
+                    .bound
+                    .iter()
+                    .map(|listen| json_string(&listen.to_string())),
+            ),
+        )
+        .raw("headers_file", path(&args.headers_file))
+        .raw("csp", json_optional(args.csp.as_deref().map(json_string)))
+        .raw("csp_all_responses", args.csp_all_responses)
+        .raw("csp_report_only", args.csp_report_only)
+        .raw(
+            "max_bytes_per_connection",
+            json_optional(args.max_bytes_per_connection),
+        )
+        .raw("precompressed", args.precompressed)
+        .build()
+}
+
+fn handle_debug_path(debug_path: &str, http_request: &HttpRequest) -> HttpResponse {
+    match debug_path {
+        "echo" => {
+            let body = JsonObject::new()
+                .string("method", http_request.method().unwrap_or_default())
+                .string("path", http_request.path().unwrap_or_default())
+                .raw("headers", json_object(&http_request.headers))
+                .raw("trailers", json_object(&http_request.trailers))
+                .string("body", &String::from_utf8_lossy(&http_request.body))
+                .build();
+            StatusLine::Ok(Some(body.into()), ContentType::ApplicationJson).into()
+        }
+        // Verbatim, before any lenient normalisation, for diagnosing odd client framing
+        "request-line" => StatusLine::Ok(
+            Some(http_request.request_line.clone().into_bytes()),
+            ContentType::TextPlain,
+        )
+        .into(),
+        // Set-Cookie can't be comma-folded, each cookie needs a line of its own
+        "cookies" => HttpResponse::from(StatusLine::NoContent)
+            .with_header(SET_COOKIE, "debug-session=1; Path=/; HttpOnly")
+            .with_header(SET_COOKIE, "debug-theme=dark; Path=/"),
+        _ => StatusLine::NotFound.into(),
+    }
+}
+
+/// Echoes are a pure function of the path, so they get a content hash ETag
+/// and clients that already hold it are told nothing changed.
+fn handle_echo(s: &str, http_request: &HttpRequest) -> HttpResponse {
+    let etag = format!("\"{:016x}\"", fnv1a(s.as_bytes()));
+    let status_line = if etag_matches(http_request.header(IF_NONE_MATCH), &etag) {
+        StatusLine::NotModified
+    } else {
+        StatusLine::Ok(Some(s.into()), ContentType::TextPlain)
+    };
+    HttpResponse::from(status_line).with_header(ETAG, etag)
+}
+
+/// Weak comparison against an If-None-Match list, as RFC 7232 asks for.
+fn etag_matches(if_none_match: Option<&str>, etag: &str) -> bool {
+    let strip_weak = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    if_none_match.is_some_and(|candidates| {
+        candidates
+            .split(',')
+            .any(|candidate| candidate.trim() == "*" || strip_weak(candidate) == strip_weak(etag))
+    })
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn handle_favicon(http_request: &HttpRequest, state: &ServerState) -> HttpResponse {
+    let custom = served_directory(http_request, &state.args)
+        .map(|directory| directory.join("favicon.ico"))
+        // Anything but a regular file (a FIFO, say) could block the read forever
+        .filter(|path| path.is_file())
+        .and_then(|path| read_file(&path, state).ok())
+        .map(|(favicon, _)| favicon);
+    let favicon = custom.unwrap_or_else(|| DEFAULT_FAVICON.to_vec());
+    HttpResponse::from(StatusLine::Ok(Some(favicon), ContentType::ImageXIcon)).with_header(
+        CACHE_CONTROL,
+        format!("public, max-age={}", FAVICON_MAX_AGE),
+    )
+}
+
+fn handle_echo_body(http_request: &HttpRequest) -> HttpResponse {
+    let response: HttpResponse =
+        StatusLine::Ok(Some(http_request.body.clone()), ContentType::TextPlain).into();
+    // Lets clients spot a body that got mangled or cut short on the way in
+    match http_request.header(CONTENT_LENGTH) {
+        Some(content_length) => response.with_header("X-Original-Content-Length", content_length),
+        None => response,
+    }
+}
+
+fn handle_user_agent(http_request: &HttpRequest) -> StatusLine {
+    http_request
+        .header(USER_AGENT)
+        .map_or(StatusLine::NotFound, |user_agent| {
+            StatusLine::Ok(Some(user_agent.into()), ContentType::TextPlain)
+        })
+}
+
+fn resolve_path(request: &str) -> Option<&str> {
+    let request_header = request.lines().next()?;
+    request_header.split_whitespace().nth(1)
+}
+
+/// Only trusted once `validate_request` has ruled out junk and conflicting duplicates.
+fn find_content_length(http_request: &HttpRequest) -> usize {
+    http_request
+        .header(CONTENT_LENGTH)
+        .and_then(parse_content_length)
+        .unwrap_or(0)
+}
+
+/// Strictly digits; `usize::from_str` would also let a leading `+` through.
+fn parse_content_length(value: &str) -> Option<usize> {
+    let value = value.trim();
+    if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok()
+}
+
+fn is_chunked(transfer_encoding: &str) -> bool {
+    transfer_encoding
+        .rsplit(',')
+        .next()
+        .map(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+        .unwrap_or(false)
+}
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn json_object(pairs: &[(String, String)]) -> String {
+    pairs
+        .iter()
+        .fold(JsonObject::new(), |object, (name, value)| {
+            object.string(name, value)
+        })
+        .build()
+}
+
+fn json_array(items: impl IntoIterator<Item = String>) -> String {
+    format!("[{}]", items.into_iter().collect::<Vec<_>>().join(","))
+}
+
+fn json_optional<T: std::fmt::Display>(value: Option<T>) -> String {
+    value.map_or_else(|| "null".to_string(), |value| value.to_string())
+}
+
+/// Assembles a JSON object one already-encoded field at a time.
+struct JsonObject {
+    fields: Vec<String>,
+}
+
+impl JsonObject {
+    fn new() -> JsonObject {
+        JsonObject { fields: Vec::new() }
+    }
+
+    /// Adds a field whose value is already valid JSON (numbers, bools, nested values).
+    fn raw(mut self, name: &str, value: impl std::fmt::Display) -> JsonObject {
+        self.fields.push(format!("{}:{}", json_string(name), value));
+        self
+    }
+
+    fn string(self, name: &str, value: &str) -> JsonObject {
+        self.raw(name, json_string(value))
+    }
+
+    fn build(self) -> String {
+        format!("{{{}}}", self.fields.join(","))
+    }
+}
+/// Formats a timestamp as an RFC 7231 IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+fn http_date(time: SystemTime) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0);
+    let days = secs / 86_400;
+    let (hour, minute, second) = (secs % 86_400 / 3600, secs % 3600 / 60, secs % 60);
+
+    // Civil-from-days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        hour,
+        minute,
+        second
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn chunked_has_to_be_the_final_coding() {
+        assert!(is_chunked("chunked"));
+        assert!(is_chunked("gzip, Chunked "));
+        assert!(!is_chunked("chunked, gzip"));
+        assert!(!is_chunked("identity"));
+        assert!(!is_chunked(""));
+    }
+
+    #[test]
+    fn cache_rules_match_extensions_case_insensitively() {
+        let rules: Vec<CacheRule> = ["png=86400", ".CSS=60"]
+            .iter()
+            .map(|rule| rule.parse().unwrap())
+            .collect();
+        assert_eq!(cache_max_age(Path::new("a/logo.PNG"), &rules), Some(86400));
+        assert_eq!(cache_max_age(Path::new("site.css"), &rules), Some(60));
+        assert_eq!(cache_max_age(Path::new("notes.txt"), &rules), None);
+        assert_eq!(cache_max_age(Path::new("README"), &rules), None);
+        assert!("png".parse::<CacheRule>().is_err());
+        assert!("png=soon".parse::<CacheRule>().is_err());
+    }
+
+    #[test]
+    fn http_dates_are_imf_fixdate() {
+        let at = |secs| http_date(UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(at(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        // RFC 7231's own example
+        assert_eq!(at(784_111_777), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(at(951_782_400), "Tue, 29 Feb 2000 00:00:00 GMT");
+        assert_eq!(at(1_735_689_599), "Tue, 31 Dec 2024 23:59:59 GMT");
+    }
+
+    #[test]
+    fn content_lengths_are_digits_only() {
+        assert_eq!(parse_content_length("0"), Some(0));
+        assert_eq!(parse_content_length(" 42 "), Some(42));
+        assert_eq!(parse_content_length("+5"), None);
+        assert_eq!(parse_content_length("-5"), None);
+        assert_eq!(parse_content_length("5x"), None);
+        assert_eq!(parse_content_length("5, 5"), None);
+        assert_eq!(parse_content_length(""), None);
+        assert_eq!(parse_content_length("99999999999999999999999"), None);
+    }
+
+    #[test]
+    fn json_strings_are_escaped() {
+        assert_eq!(json_string("plain"), r#""plain""#);
+        assert_eq!(json_string("a\"b\\c"), r#""a\"b\\c""#);
+        assert_eq!(json_string("line\r\n\tend"), r#""line\r\n\tend""#);
+        assert_eq!(json_string("\u{1}"), r#""\u0001""#);
+        assert_eq!(json_string("é"), r#""é""#);
+    }
+
+    #[test]
+    fn parent_and_absolute_paths_escape_the_directory() {
+        assert!(escapes_directory("../secret"));
+        assert!(escapes_directory("a/../../secret"));
+        assert!(escapes_directory("a/.."));
+        assert!(escapes_directory("/etc/passwd"));
+        assert!(!escapes_directory("a/b.txt"));
+        assert!(!escapes_directory("./a"));
+        assert!(!escapes_directory("..a/b.."));
+        assert!(!escapes_directory(""));
+    }
+
+    #[test]
+    fn etags_match_weakly_and_by_wildcard() {
+        let etag = "\"abc\"";
+        assert!(etag_matches(Some("\"abc\""), etag));
+        assert!(etag_matches(Some("W/\"abc\""), etag));
+        assert!(etag_matches(Some("\"x\", \"abc\""), etag));
+        assert!(etag_matches(Some("*"), etag));
+        assert!(!etag_matches(Some("\"abcd\""), etag));
+        assert!(!etag_matches(Some("abc"), etag));
+        assert!(!etag_matches(None, etag));
+    }
+
+    #[test]
+    fn fnv1a_matches_the_reference_values() {
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn content_types_follow_the_extension() {
+        let content_type = |path: &str| content_type_for_path(Path::new(path)).as_str();
+        assert_eq!(content_type("photo.webp"), "image/webp");
+        assert_eq!(content_type("photo.AVIF"), "image/avif");
+        assert_eq!(content_type("icon.svg"), "image/svg+xml");
+        assert_eq!(content_type("app.wasm"), "application/wasm");
+        assert_eq!(content_type("font.woff2"), "font/woff2");
+        assert_eq!(content_type("index.htm"), "text/html");
+        assert_eq!(content_type("archive.tar"), "application/octet-stream");
+        assert_eq!(content_type("Makefile"), "application/octet-stream");
+    }
+
+    #[test]
+    fn allowed_paths_match_whole_segments() {
+        let allow = ["/echo".to_string(), "/files/public/".to_string()];
+        assert!(path_allowed("/echo", &allow));
+        assert!(path_allowed("/echo/hi", &allow));
+        assert!(!path_allowed("/echoes", &allow));
+        assert!(path_allowed("/files/public/a.txt", &allow));
+        assert!(!path_allowed("/files/private/a.txt", &allow));
+        assert!(!path_allowed("/", &allow));
+        assert!(path_allowed("/anything", &[]));
+    }
+
+    #[test]
+    fn credentials_are_redacted_from_logged_headers() {
+        let headers: Headers = [
+            ("authorization", "Bearer s3cret"),
+            ("Proxy-Authorization", "Basic czNjcmV0"),
+            ("Cookie", "session=s3cret"),
+            ("Set-Cookie", "session=s3cret"),
+            ("Accept", "*/*"),
+        ]
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+        let redacted = redact_headers(&headers);
+        let values: Vec<&str> = redacted.iter().map(|(_, value)| value.as_str()).collect();
+        assert_eq!(
+            values,
+            [
+                "[redacted]",
+                "[redacted]",
+                "[redacted]",
+                "[redacted]",
+                "*/*"
+            ]
+        );
+        assert_eq!(redacted[0].0, "authorization");
+    }
+
+    fn state_for(flags: &[&str]) -> ServerState {
+        let args = Args::parse_from(std::iter::once("http-server").chain(flags.iter().copied()));
+        ServerState {
+            args,
+            ready: AtomicBool::new(true),
+            shutdown: AtomicBool::new(false),
+            file_cache: None,
+            requests_served: AtomicU64::new(0),
+            busy_page: None,
+            instance_id: "test".to_string(),
+            default_headers: Vec::new(),
+            upload_locks: PathLocks::default(),
+            bound: Vec::new(),
+            in_flight: Mutex::new(0),
+            all_finished: Condvar::new(),
+        }
+    }
+
+    #[test]
+    fn the_backlog_is_refused_once_shutdown_starts() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let clients: Vec<TcpStream> = (0..2)
+            .map(|_| {
+                let mut client = TcpStream::connect(addr).unwrap();
+                client
+                    .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                    .unwrap();
+                client
+            })
+            .collect();
+
+        let state = state_for(&[]);
+        state.shutdown.store(true, Ordering::SeqCst);
+        let (first, _) = listener.accept().unwrap();
+        refuse_backlog(first, &listener, ListenerRole::All, &state);
+
+        for mut client in clients {
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            assert!(
+                response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+                "{}",
+                response
+            );
+            assert!(
+                response.contains("\r\nConnection: close\r\n"),
+                "{}",
+                response
+            );
+        }
+    }
+
+    #[test]
+    fn long_names_are_cut_with_an_ellipsis() {
+        assert_eq!(truncate_name("short", 5), "short");
+        assert_eq!(truncate_name("longer", 5), "long…");
+        assert_eq!(truncate_name("ééééééé", 3), "éé…");
+        assert_eq!(truncate_name("name", 0), "…");
+    }
+
+    #[test]
+    fn preload_rules_hint_each_asset_as_what_it_is() {
+        let rule: PreloadRule = ".HTML=/site.css, /app.js,,/font.woff2".parse().unwrap();
+        assert_eq!(rule.extension, "html");
+        assert_eq!(rule.assets, ["/site.css", "/app.js", "/font.woff2"]);
+        assert!("html".parse::<PreloadRule>().is_err());
+        assert!("html=/a>b".parse::<PreloadRule>().is_err());
+
+        assert_eq!(
+            preload_link("/site.css"),
+            "</site.css>; rel=preload; as=style"
+        );
+        assert_eq!(
+            preload_link("/app.mjs"),
+            "</app.mjs>; rel=preload; as=script"
+        );
+        assert_eq!(
+            preload_link("/font.woff2"),
+            "</font.woff2>; rel=preload; as=font; crossorigin"
+        );
+        assert_eq!(
+            preload_link("/logo.PNG"),
+            "</logo.PNG>; rel=preload; as=image"
+        );
+        assert_eq!(preload_link("/data"), "</data>; rel=preload; as=fetch");
+    }
+
+    #[test]
+    fn listen_addresses_take_an_optional_role() {
+        let listen: Listen = "127.0.0.1:8080".parse().unwrap();
+        assert_eq!(listen.addr, "127.0.0.1:8080".parse().unwrap());
+        assert!(matches!(listen.role, ListenerRole::All));
+        let listen: Listen = "[::1]:9090:Admin".parse().unwrap();
+        assert_eq!(listen.addr, "[::1]:9090".parse().unwrap());
+        assert!(matches!(listen.role, ListenerRole::Admin));
+        assert!("127.0.0.1:8080:backstage".parse::<Listen>().is_err());
+        assert!("localhost".parse::<Listen>().is_err());
+    }
+
+    #[test]
+    fn roles_split_internal_routes_from_public_ones() {
+        assert!(ListenerRole::Public.serves("/files/a"));
+        assert!(!ListenerRole::Public.serves("/admin/config"));
+        assert!(!ListenerRole::Public.serves("/debug/echo"));
+        assert!(ListenerRole::Admin.serves("/admin/config"));
+        assert!(ListenerRole::Admin.serves("/health"));
+        assert!(!ListenerRole::Admin.serves("/"));
+        assert!(ListenerRole::All.serves("/debug/echo"));
+    }
+
+    #[test]
+    fn percent_decode_only_takes_two_hex_digits() {
+        assert_eq!(percent_decode("/a%20b%2Fc"), b"/a b/c");
+        assert_eq!(percent_decode("%00%7f"), b"\0\x7f");
+        // Malformed escapes stay as they are, signs included
+        assert_eq!(percent_decode("%+f%-1%zz%4"), b"%+f%-1%zz%4");
+        assert_eq!(percent_decode("100%"), b"100%");
+        assert_eq!(percent_decode("%C3%A9"), "é".as_bytes());
+    }
+
+    #[test]
+    fn control_characters_are_escaped_for_the_log() {
+        assert_eq!(
+            escape_control("GET /a\r\nforged\x00 HTTP/1.1"),
+            "GET /a\\x0d\\x0aforged\\x00 HTTP/1.1"
+        );
+        assert_eq!(escape_control("GET /café HTTP/1.1"), "GET /café HTTP/1.1");
+    }
 }
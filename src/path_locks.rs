@@ -0,0 +1,45 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{Condvar, Mutex},
+};
+
+/// Per-file exclusive locks, so a check on a file and the write it decides on
+/// can't be interleaved with another request's. Only paths currently held
+/// take up any room.
+#[derive(Default)]
+pub struct PathLocks {
+    held: Mutex<HashSet<PathBuf>>,
+    released: Condvar,
+}
+
+/// Releases its path when dropped.
+pub struct PathGuard<'a> {
+    locks: &'a PathLocks,
+    path: PathBuf,
+}
+
+impl PathLocks {
+    /// Blocks until nobody else holds `path`. `None` only if a holder panicked
+    /// while touching the set itself.
+    pub fn lock(&self, path: &Path) -> Option<PathGuard<'_>> {
+        let mut held = self.held.lock().ok()?;
+        while held.contains(path) {
+            held = self.released.wait(held).ok()?;
+        }
+        held.insert(path.to_path_buf());
+        Some(PathGuard {
+            locks: self,
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for PathGuard<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut held) = self.locks.held.lock() {
+            held.remove(&self.path);
+        }
+        self.locks.released.notify_all();
+    }
+}
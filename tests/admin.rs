@@ -0,0 +1,211 @@
+mod common;
+
+use common::{Response, Server, TempDir};
+use pretty_assertions::assert_eq;
+
+const TOKEN: &str = "s3cret";
+
+fn admin(server: &Server, method: &str, path: &str) -> Response {
+    server.send(
+        format!(
+            "{} /admin/{} HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {}\r\nContent-Length: 0\r\n\r\n",
+            method, path, TOKEN
+        )
+        .as_bytes(),
+    )
+}
+
+#[test]
+fn drain_fails_health_checks() {
+    let server = Server::start(&["--admin-token", TOKEN]);
+    assert_eq!(server.get("/health").status, 200);
+
+    let response = admin(&server, "POST", "drain");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.text(), "draining");
+
+    assert_eq!(server.get("/health").status, 503);
+    // Only health checks change, everything else is still served
+    assert_eq!(server.get("/").status, 200);
+}
+
+#[test]
+fn admin_routes_need_the_token() {
+    let server = Server::start(&["--admin-token", TOKEN]);
+    let response = server.send(b"POST /admin/drain HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer wrong\r\nContent-Length: 0\r\n\r\n");
+    assert_eq!(response.status, 401);
+    assert_eq!(response.header("WWW-Authenticate"), Some("Bearer"));
+    assert_eq!(server.get("/health").status, 200);
+}
+
+#[test]
+fn admin_routes_are_off_without_a_token() {
+    let server = Server::start(&[]);
+    assert_eq!(admin(&server, "POST", "drain").status, 404);
+    assert_eq!(server.get("/health").status, 200);
+}
+
+#[test]
+fn unavailable_responses_say_when_to_retry() {
+    let server = Server::start(&["--admin-token", TOKEN, "--retry-after-secs", "7"]);
+    admin(&server, "POST", "drain");
+    let response = server.get("/health");
+    assert_eq!(response.status, 503);
+    assert_eq!(response.header("Retry-After"), Some("7"));
+}
+
+#[test]
+fn retry_after_can_be_a_date() {
+    let server = Server::start(&["--admin-token", TOKEN, "--retry-after-format", "date"]);
+    admin(&server, "POST", "drain");
+    let response = server.get("/health");
+    assert_eq!(response.status, 503);
+    let retry_after = response.header("Retry-After").unwrap();
+    assert!(retry_after.ends_with(" GMT"), "{}", retry_after);
+    assert_eq!(retry_after.len(), "Sun, 06 Nov 1994 08:49:37 GMT".len());
+}
+
+#[test]
+fn config_reports_bound_addresses_and_hides_secrets() {
+    let port = common::free_port();
+    let all = format!("127.0.0.1:{}:all", port);
+    let server = Server::start_with(
+        &["--admin-token", TOKEN, "--keep-alive-max", "7"],
+        &["--listen", &all, "--listen", "127.0.0.1:0:admin"],
+        port,
+    );
+    let response = admin(&server, "GET", "config");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("Content-Type"), Some("application/json"));
+    let config = response.text();
+    assert!(config.contains(r#""keep_alive_max":7"#), "{}", config);
+    assert!(
+        config.contains(r#""admin_token":"[redacted]""#),
+        "{}",
+        config
+    );
+    assert!(!config.contains(TOKEN), "{}", config);
+    assert!(!config.contains(r#""address""#), "{}", config);
+
+    // The OS-picked port is reported, not the 0 it was asked for
+    let bound = config.split(r#""bound":["#).nth(1).unwrap();
+    let bound = bound.split(']').next().unwrap();
+    let mut entries = bound.split(',');
+    assert_eq!(entries.next(), Some(format!("\"{}\"", all).as_str()));
+    let admin_entry = entries.next().unwrap();
+    assert!(admin_entry.ends_with(":admin\""), "{}", admin_entry);
+    assert!(!admin_entry.contains(":0:"), "{}", admin_entry);
+}
+
+#[test]
+fn config_is_read_only() {
+    let server = Server::start(&["--admin-token", TOKEN]);
+    assert_eq!(admin(&server, "POST", "config").status, 404);
+}
+
+#[test]
+fn warmup_preloads_the_served_directory() {
+    let dir = TempDir::new();
+    dir.write("a.txt", "aaaa");
+    dir.write("nested/b.txt", "bb");
+    dir.write("big.bin", vec![0; 100]);
+    let server = Server::start(&[
+        "--admin-token",
+        TOKEN,
+        "--directory",
+        dir.arg(),
+        "--file-cache",
+        "--file-cache-max-bytes",
+        "10",
+    ]);
+
+    let response = admin(&server, "POST", "warmup");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.text(), r#"{"files":2,"bytes":6,"skipped":1}"#);
+
+    let stats = admin(&server, "GET", "file-cache").text();
+    assert_eq!(stats, r#"{"entries":2,"bytes":6,"hits":0,"misses":0}"#);
+    assert_eq!(server.get("/files/nested/b.txt").text(), "bb");
+    let stats = admin(&server, "GET", "file-cache").text();
+    assert!(stats.contains(r#""hits":1"#), "{}", stats);
+}
+
+#[test]
+fn warmup_needs_the_file_cache() {
+    let dir = TempDir::new();
+    let server = Server::start(&["--admin-token", TOKEN, "--directory", dir.arg()]);
+    assert_eq!(admin(&server, "POST", "warmup").status, 404);
+}
+
+#[test]
+fn unavailable_responses_can_carry_a_busy_page() {
+    let dir = TempDir::new();
+    let page = dir.write("busy.html", "<h1>Back soon</h1>");
+    let server = Server::start(&[
+        "--admin-token",
+        TOKEN,
+        "--busy-page",
+        page.to_str().unwrap(),
+    ]);
+    admin(&server, "POST", "drain");
+    let response = server.get("/health");
+    assert_eq!(response.status, 503);
+    assert_eq!(response.header("Content-Type"), Some("text/html"));
+    assert_eq!(response.text(), "<h1>Back soon</h1>");
+}
+
+#[test]
+fn unavailable_responses_default_to_plain_text() {
+    let server = Server::start(&["--admin-token", TOKEN]);
+    admin(&server, "POST", "drain");
+    let response = server.get("/health");
+    assert_eq!(response.header("Content-Type"), Some("text/plain"));
+    assert_eq!(
+        response.text(),
+        "Service Unavailable, please try again later"
+    );
+}
+
+#[test]
+fn a_missing_busy_page_stops_startup() {
+    let dir = TempDir::new();
+    let missing = dir.path().join("busy.html");
+    let (_, _, stderr) = common::run_to_exit(&[
+        "--busy-page",
+        missing.to_str().unwrap(),
+        "--listen",
+        "127.0.0.1:0",
+    ]);
+    assert!(stderr.contains("busy.html"), "{}", stderr);
+}
+
+#[test]
+fn listeners_only_answer_the_routes_of_their_role() {
+    let (public_port, admin_port) = (common::free_port(), common::free_port());
+    let public = format!("127.0.0.1:{}:public", public_port);
+    let internal = format!("127.0.0.1:{}:admin", admin_port);
+    let server = Server::start_with(
+        &["--admin-token", TOKEN, "--enable-debug-routes"],
+        &["--listen", &public, "--listen", &internal],
+        public_port,
+    );
+    assert_eq!(server.get("/echo/a").status, 200);
+    assert_eq!(admin(&server, "GET", "config").status, 404);
+    assert_eq!(server.get("/debug/cookies").status, 404);
+
+    let on_admin_port = |target: &str| {
+        let mut connection = common::Connection::open(admin_port);
+        connection.write(
+            format!(
+                "GET {} HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {}\r\n\r\n",
+                target, TOKEN
+            )
+            .as_bytes(),
+        );
+        connection.read_response(false).status
+    };
+    assert_eq!(on_admin_port("/admin/config"), 200);
+    assert_eq!(on_admin_port("/debug/cookies"), 204);
+    assert_eq!(on_admin_port("/health"), 200);
+    assert_eq!(on_admin_port("/echo/a"), 404);
+}
@@ -0,0 +1,37 @@
+mod common;
+
+use common::Server;
+use pretty_assertions::assert_eq;
+use std::thread;
+
+fn serves_a_burst(args: &[&str]) {
+    let server = Server::start(args);
+    thread::scope(|scope| {
+        let clients: Vec<_> = (0..32)
+            .map(|i| {
+                let server = &server;
+                scope.spawn(move || server.get(&format!("/echo/{}", i)))
+            })
+            .collect();
+        for (i, client) in clients.into_iter().enumerate() {
+            let response = client.join().unwrap();
+            assert_eq!(response.status, 200);
+            assert_eq!(response.text(), i.to_string());
+        }
+    });
+}
+
+#[test]
+fn thread_per_connection_serves_a_burst() {
+    serves_a_burst(&["--concurrency-model", "thread-per-connection"]);
+}
+
+#[test]
+fn pool_serves_a_burst() {
+    serves_a_burst(&["--concurrency-model", "pool", "--threads", "2"]);
+}
+
+#[test]
+fn work_stealing_serves_a_burst() {
+    serves_a_burst(&["--concurrency-model", "work-stealing", "--threads", "2"]);
+}
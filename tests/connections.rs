@@ -0,0 +1,151 @@
+mod common;
+
+use common::{run_to_exit, Server};
+use pretty_assertions::assert_eq;
+use std::{net::Shutdown, time::Duration};
+
+#[test]
+fn empty_connections_close_quietly() {
+    let server = Server::start(&[]);
+    let mut connection = server.connect();
+    connection.stream().shutdown(Shutdown::Write).unwrap();
+    assert_eq!(connection.read_to_end(), b"");
+
+    assert_eq!(server.get("/").status, 200);
+    assert_eq!(server.stderr(), Vec::<String>::new());
+    // Only the real request made it into the access log
+    server.wait_for_line("\"GET / HTTP/1.1\"");
+    assert_eq!(server.stdout().len(), 2);
+}
+
+#[test]
+fn blank_request_lines_get_400() {
+    let server = Server::start(&[]);
+    let mut connection = server.connect();
+    connection.write(b"\r\n\r\n");
+    let response = connection.read_response(false);
+    assert_eq!(response.status, 400);
+    assert!(connection.is_closed(Duration::from_secs(5)));
+}
+
+#[test]
+fn keep_alive_counts_down_to_the_last_request() {
+    let server = Server::start(&["--keep-alive-max", "3", "--keep-alive-timeout-secs", "9"]);
+    let mut connection = server.connect();
+    for remaining in [2, 1] {
+        connection.write(b"GET /echo/hi HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let response = connection.read_response(false);
+        assert_eq!(response.text(), "hi");
+        let keep_alive = format!("timeout=9, max={}", remaining);
+        assert_eq!(response.header("Keep-Alive"), Some(keep_alive.as_str()));
+        assert_eq!(response.header("Connection"), None);
+    }
+    connection.write(b"GET /echo/hi HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    let response = connection.read_response(false);
+    assert_eq!(response.header("Keep-Alive"), None);
+    assert_eq!(response.header("Connection"), Some("close"));
+    assert!(connection.is_closed(Duration::from_secs(5)));
+}
+
+#[test]
+fn pipelined_requests_are_answered_in_order() {
+    let server = Server::start(&[]);
+    let mut connection = server.connect();
+    connection.write(
+        b"GET /echo/one HTTP/1.1\r\nHost: localhost\r\n\r\n\
+          POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\n\r\ntwo\
+          GET /echo/three HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    );
+    for expected in ["one", "two", "three"] {
+        assert_eq!(connection.read_response(false).text(), expected);
+    }
+    assert!(connection.is_closed(Duration::from_secs(5)));
+}
+
+#[test]
+fn http_1_0_closes_unless_asked_to_keep_alive() {
+    let server = Server::start(&[]);
+    let mut connection = server.connect();
+    connection.write(b"GET /echo/a HTTP/1.0\r\n\r\n");
+    let response = connection.read_response(false);
+    assert_eq!(response.header("Connection"), Some("close"));
+    assert!(connection.is_closed(Duration::from_secs(5)));
+
+    let mut connection = server.connect();
+    connection.write(b"GET /echo/a HTTP/1.0\r\nConnection: keep-alive\r\n\r\n");
+    let response = connection.read_response(false);
+    assert_eq!(response.header("Connection"), Some("keep-alive"));
+    assert!(response.header("Keep-Alive").is_some());
+    connection.write(b"GET /echo/b HTTP/1.0\r\nConnection: keep-alive\r\n\r\n");
+    assert_eq!(connection.read_response(false).text(), "b");
+}
+
+#[test]
+fn a_zero_keep_alive_timeout_stops_startup() {
+    let (_, _, stderr) =
+        run_to_exit(&["--keep-alive-timeout-secs", "0", "--listen", "127.0.0.1:0"]);
+    assert!(stderr.contains("--keep-alive-timeout-secs"), "{}", stderr);
+}
+
+#[test]
+fn idle_connections_time_out() {
+    let server = Server::start(&["--keep-alive-timeout-secs", "1"]);
+    let mut connection = server.connect();
+    connection.write(b"GET /echo/a HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    connection.read_response(false);
+    assert!(!connection.is_closed(Duration::from_millis(300)));
+    assert!(connection.is_closed(Duration::from_secs(5)));
+}
+
+/// What the response took on the wire, as the server wrote it.
+fn wire_len(response: &common::Response) -> usize {
+    let status_line = format!("HTTP/1.1 {} OK\r\n", response.status).len();
+    let headers: usize = response
+        .headers
+        .iter()
+        .map(|(name, value)| name.len() + value.len() + 4)
+        .sum();
+    status_line + headers + 2 + response.body.len()
+}
+
+#[test]
+fn the_byte_budget_counts_response_heads() {
+    let server = Server::start(&["--max-bytes-per-connection", "400"]);
+    let mut connection = server.connect();
+    let mut sent = 0;
+    for _ in 0..10 {
+        connection.write(b"GET /echo/hi HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let response = connection.read_response(false);
+        sent += wire_len(&response);
+        if response.header("Connection") == Some("close") {
+            // Only the response that reaches the budget is the last
+            assert!(sent >= 400, "closed after {} bytes", sent);
+            assert!(connection.is_closed(Duration::from_secs(5)));
+            return;
+        }
+        assert!(sent < 400, "kept alive after {} bytes", sent);
+    }
+    panic!("2-byte bodies never used up the budget, heads weren't counted");
+}
+
+#[test]
+fn peers_that_stop_reading_are_let_go_quietly() {
+    let server = Server::start(&["--concurrency-model", "pool", "--threads", "1"]);
+    let body = vec![b'x'; 4 * 1024 * 1024];
+    let mut connection = server.connect();
+    connection.write(
+        format!(
+            "POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        )
+        .as_bytes(),
+    );
+    connection.write(&body);
+    connection.stream().shutdown(Shutdown::Both).unwrap();
+    drop(connection);
+
+    server.wait_for_line("\"POST /echo HTTP/1.1\" 200");
+    // The only worker is free again, and nothing was reported as an error
+    assert_eq!(server.get("/echo/after").text(), "after");
+    assert_eq!(server.stderr(), Vec::<String>::new());
+}
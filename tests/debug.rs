@@ -0,0 +1,55 @@
+mod common;
+
+use common::Server;
+use pretty_assertions::assert_eq;
+
+#[test]
+fn echo_reports_trailers_apart_from_headers() {
+    let server = Server::start(&["--enable-debug-routes"]);
+    let response = server.send(
+        b"POST /debug/echo HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n\
+          2\r\nhi\r\n0\r\nX-Checksum: abc\r\n\r\n",
+    );
+    assert_eq!(response.status, 200);
+    let body = response.text();
+    assert!(
+        body.contains(r#""trailers":{"X-Checksum":"abc"}"#),
+        "{}",
+        body
+    );
+    assert!(
+        body.contains(r#""headers":{"Host":"localhost","Transfer-Encoding":"chunked"}"#),
+        "{}",
+        body
+    );
+    assert!(body.contains(r#""body":"hi""#), "{}", body);
+}
+
+#[test]
+fn debug_routes_are_off_by_default() {
+    let server = Server::start(&[]);
+    assert_eq!(server.get("/debug/echo").status, 404);
+}
+
+#[test]
+fn request_line_is_reflected_verbatim() {
+    let server = Server::start(&["--enable-debug-routes", "--lenient-methods"]);
+    let response =
+        server.send(b"get /debug/request-line?x=%20y HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.text(), "get /debug/request-line?x=%20y HTTP/1.1");
+}
+
+#[test]
+fn each_cookie_gets_its_own_set_cookie_line() {
+    let server = Server::start(&["--enable-debug-routes"]);
+    let response = server.get("/debug/cookies");
+    assert_eq!(response.status, 204);
+    assert_eq!(
+        response.headers_named("Set-Cookie"),
+        [
+            "debug-session=1; Path=/; HttpOnly",
+            "debug-theme=dark; Path=/"
+        ]
+    );
+}
@@ -0,0 +1,725 @@
+mod common;
+
+use common::{Server, TempDir};
+use pretty_assertions::assert_eq;
+
+#[test]
+fn cache_rules_set_cache_control_by_extension() {
+    let dir = TempDir::new();
+    dir.write("logo.png", "png");
+    dir.write("notes.txt", "text");
+    let server = Server::start(&["--directory", dir.arg(), "--cache-rule", "png=86400"]);
+
+    let response = server.get("/files/logo.png");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("Cache-Control"), Some("max-age=86400"));
+
+    let response = server.get("/files/notes.txt");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("Cache-Control"), None);
+}
+
+#[test]
+fn post_into_a_missing_directory_is_answered() {
+    let dir = TempDir::new();
+    let server = Server::start(&["--directory", dir.arg()]);
+    let response = server
+        .send(b"POST /files/nodir/x HTTP/1.1\r\nHost: localhost\r\nContent-Length: 1\r\n\r\nx");
+    assert_eq!(response.status, 404);
+    assert_eq!(server.get("/").status, 200);
+}
+
+#[test]
+fn failed_writes_other_than_a_missing_directory_get_500() {
+    let dir = TempDir::new();
+    dir.write("notes.txt", "text");
+    let server = Server::start(&["--directory", dir.arg()]);
+    // A regular file can't be a parent directory
+    let response = server
+        .send(b"POST /files/notes.txt/x HTTP/1.1\r\nHost: localhost\r\nContent-Length: 1\r\n\r\nx");
+    assert_eq!(response.status, 500);
+    assert_eq!(server.get("/files/notes.txt").text(), "text");
+}
+
+fn traversal_attempts(server: &Server) -> Vec<u16> {
+    [
+        "/files/../secret.txt",
+        "/files/sub/../../secret.txt",
+        "/files//etc/passwd",
+        "/files/%2e%2e/secret.txt",
+    ]
+    .iter()
+    .map(|target| server.get(target).status)
+    .collect()
+}
+
+#[test]
+fn traversal_is_404_by_default() {
+    let root = TempDir::new();
+    root.write("secret.txt", "secret");
+    root.write("served/sub/inside.txt", "inside");
+    let served = root.path().join("served");
+    let server = Server::start(&["--directory", served.to_str().unwrap()]);
+
+    assert_eq!(traversal_attempts(&server), [404, 404, 404, 404]);
+    assert_eq!(server.get("/files/sub/inside.txt").text(), "inside");
+}
+
+#[test]
+fn traversal_can_be_403() {
+    let root = TempDir::new();
+    root.write("secret.txt", "secret");
+    root.write("served/sub/inside.txt", "inside");
+    let served = root.path().join("served");
+    let server = Server::start(&[
+        "--directory",
+        served.to_str().unwrap(),
+        "--traversal-response",
+        "403",
+    ]);
+
+    assert_eq!(traversal_attempts(&server), [403, 403, 403, 403]);
+    assert_eq!(server.get("/files/sub/inside.txt").status, 200);
+}
+
+#[test]
+fn modern_image_types_are_labelled() {
+    let dir = TempDir::new();
+    dir.write("photo.webp", "webp");
+    dir.write("photo.avif", "avif");
+    let server = Server::start(&["--directory", dir.arg()]);
+    let response = server.get("/files/photo.webp");
+    assert_eq!(response.header("Content-Type"), Some("image/webp"));
+    assert_eq!(response.text(), "webp");
+    let response = server.get("/files/photo.avif");
+    assert_eq!(response.header("Content-Type"), Some("image/avif"));
+}
+
+#[test]
+fn uploads_are_checked_against_their_digests() {
+    let dir = TempDir::new();
+    let server = Server::start(&["--directory", dir.arg()]);
+    let upload = |name: &str, header: &str| {
+        server
+            .send(
+                format!(
+                    "POST /files/{} HTTP/1.1\r\nHost: localhost\r\n{}\r\nContent-Length: 5\r\n\r\nhello",
+                    name, header
+                )
+                .as_bytes(),
+            )
+            .status
+    };
+
+    assert_eq!(upload("md5", "Content-MD5: XUFAKrxLKna5cZ2REBfFkg=="), 201);
+    assert_eq!(
+        upload(
+            "sha",
+            "Digest: sha-256=LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ="
+        ),
+        201
+    );
+    assert_eq!(upload("bad", "Content-MD5: AAAAAAAAAAAAAAAAAAAAAA=="), 400);
+    assert_eq!(std::fs::read(dir.path().join("md5")).unwrap(), b"hello");
+    assert!(!dir.path().join("bad").exists());
+}
+
+fn patch(server: &Server, name: &str, offset: &str, body: &str) -> common::Response {
+    server.send(
+        format!(
+            "PATCH /files/{} HTTP/1.1\r\nHost: localhost\r\nUpload-Offset: {}\r\nContent-Length: {}\r\n\r\n{}",
+            name,
+            offset,
+            body.len(),
+            body
+        )
+        .as_bytes(),
+    )
+}
+
+#[test]
+fn patch_appends_at_the_stated_offset() {
+    let dir = TempDir::new();
+    let server = Server::start(&["--directory", dir.arg()]);
+
+    let response = patch(&server, "upload", "0", "abc");
+    assert_eq!(response.status, 204);
+    assert_eq!(response.header("Upload-Offset"), Some("3"));
+    let response = patch(&server, "upload", "3", "def");
+    assert_eq!(response.header("Upload-Offset"), Some("6"));
+
+    // A retried piece is refused and told where the file really ends
+    let response = patch(&server, "upload", "3", "def");
+    assert_eq!(response.status, 409);
+    assert_eq!(response.header("Upload-Offset"), Some("6"));
+    assert_eq!(std::fs::read(dir.path().join("upload")).unwrap(), b"abcdef");
+
+    assert_eq!(patch(&server, "upload", "six", "x").status, 400);
+}
+
+#[test]
+fn a_late_piece_does_not_create_the_file() {
+    let dir = TempDir::new();
+    let server = Server::start(&["--directory", dir.arg()]);
+    let response = patch(&server, "upload", "5", "late");
+    assert_eq!(response.status, 409);
+    assert_eq!(response.header("Upload-Offset"), Some("0"));
+    assert!(!dir.path().join("upload").exists());
+}
+
+#[test]
+fn failed_appends_other_than_a_missing_directory_get_500() {
+    let dir = TempDir::new();
+    dir.write("notes.txt", "text");
+    let server = Server::start(&["--directory", dir.arg()]);
+    assert_eq!(patch(&server, "nodir/x", "0", "abc").status, 404);
+    // A regular file can't be a parent directory
+    assert_eq!(patch(&server, "notes.txt/x", "0", "abc").status, 500);
+    assert_eq!(server.get("/files/notes.txt").text(), "text");
+}
+
+#[test]
+fn racing_pieces_for_one_offset_land_once() {
+    let dir = TempDir::new();
+    let server = Server::start(&["--directory", dir.arg()]);
+    let piece = "x".repeat(4096);
+    let statuses: Vec<u16> = std::thread::scope(|scope| {
+        let racers: Vec<_> = (0..16)
+            .map(|_| scope.spawn(|| patch(&server, "upload", "0", &piece).status))
+            .collect();
+        racers
+            .into_iter()
+            .map(|racer| racer.join().unwrap())
+            .collect()
+    });
+    assert_eq!(statuses.iter().filter(|&&status| status == 204).count(), 1);
+    assert_eq!(statuses.iter().filter(|&&status| status == 409).count(), 15);
+    assert_eq!(
+        std::fs::metadata(dir.path().join("upload")).unwrap().len(),
+        4096
+    );
+}
+
+#[test]
+fn head_reports_the_file_length_without_a_body() {
+    let dir = TempDir::new();
+    dir.write("big.txt", "x".repeat(12345));
+    let server = Server::start(&[
+        "--directory",
+        dir.arg(),
+        "--cache-rule",
+        "txt=60",
+        "--file-cache",
+        "--admin-token",
+        "s3cret",
+    ]);
+    let mut connection = server.connect();
+    connection.write(b"HEAD /files/big.txt HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    let response = connection.read_response(true);
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("Content-Length"), Some("12345"));
+    assert_eq!(response.header("Content-Type"), Some("text/plain"));
+    // Same file headers as a GET would get
+    assert_eq!(response.header("Cache-Control"), Some("max-age=60"));
+
+    // Nothing trails the head, so the next response starts right away
+    connection.write(b"GET /echo/next HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(connection.read_response(false).text(), "next");
+
+    // The length came from metadata, the file itself was never read
+    let stats = server
+        .send(b"GET /admin/file-cache HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer s3cret\r\n\r\n")
+        .text();
+    assert!(stats.contains(r#""misses":0"#), "{}", stats);
+}
+
+#[test]
+fn directories_redirect_to_their_trailing_slash() {
+    let dir = TempDir::new();
+    dir.write("sub/inner.txt", "inner");
+    let server = Server::start(&["--directory", dir.arg(), "--trailing-slash-redirect"]);
+
+    let response = server.get("/files/sub?sort=name");
+    assert_eq!(response.status, 301);
+    assert_eq!(response.header("Location"), Some("/files/sub/?sort=name"));
+    assert_eq!(server.get("/files/sub/inner.txt").status, 200);
+
+    // Writes aren't redirected, a client might not replay the body
+    let response =
+        server.send(b"POST /files/sub HTTP/1.1\r\nHost: localhost\r\nContent-Length: 1\r\n\r\nx");
+    assert_ne!(response.status, 301);
+    assert!(dir.path().join("sub").is_dir());
+}
+
+#[test]
+fn directories_are_not_redirected_by_default() {
+    let dir = TempDir::new();
+    dir.write("sub/inner.txt", "inner");
+    let server = Server::start(&["--directory", dir.arg()]);
+    assert_eq!(server.get("/files/sub").status, 404);
+}
+
+fn mkfifo(path: &std::path::Path) {
+    let status = std::process::Command::new("mkfifo")
+        .arg(path)
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+#[test]
+fn fifos_are_never_opened() {
+    let dir = TempDir::new();
+    mkfifo(&dir.path().join("pipe"));
+    mkfifo(&dir.path().join("favicon.ico"));
+    let server = Server::start(&["--directory", dir.arg()]);
+
+    assert_eq!(server.get("/files/pipe").status, 404);
+    let favicon = server.get("/favicon.ico");
+    assert_eq!(favicon.status, 200);
+    assert_eq!(favicon.body, include_bytes!("../assets/favicon.ico"));
+}
+
+#[test]
+fn long_uploads_log_their_progress() {
+    let dir = TempDir::new();
+    let server = Server::start(&["--directory", dir.arg(), "--upload-progress-bytes", "4"]);
+    let response = server.send(
+        b"POST /files/sized HTTP/1.1\r\nHost: localhost\r\nContent-Length: 10\r\n\r\n0123456789",
+    );
+    assert_eq!(response.status, 201);
+    server.wait_for_line("POST /files/sized");
+    let progress: Vec<String> = server
+        .stdout()
+        .into_iter()
+        .filter(|line| line.starts_with("upload "))
+        .collect();
+    assert_eq!(progress, ["upload /files/sized: 10/10 bytes"]);
+
+    let response = server.send(
+        b"POST /files/chunked HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n\
+          3\r\nabc\r\n3\r\ndef\r\n3\r\nghi\r\n0\r\n\r\n",
+    );
+    assert_eq!(response.status, 201);
+    server.wait_for_line("POST /files/chunked");
+    assert!(server
+        .stdout()
+        .contains(&"upload /files/chunked: 6 bytes".to_string()));
+    assert!(server
+        .stdout()
+        .contains(&"upload /files/chunked: 9 bytes".to_string()));
+}
+
+#[test]
+fn create_only_posts_never_overwrite() {
+    let dir = TempDir::new();
+    dir.write("taken.txt", "original");
+    let server = Server::start(&["--directory", dir.arg(), "--post-create-only"]);
+    let post = |name: &str| {
+        server.send(
+            format!(
+                "POST /files/{} HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\n\r\nnew",
+                name
+            )
+            .as_bytes(),
+        )
+    };
+    assert_eq!(post("taken.txt").status, 409);
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("taken.txt")).unwrap(),
+        "original"
+    );
+    assert_eq!(post("fresh.txt").status, 201);
+    assert_eq!(post("fresh.txt").status, 409);
+    assert_eq!(server.get("/files/fresh.txt").text(), "new");
+}
+
+#[test]
+fn followed_files_stream_appends_until_a_cap() {
+    let dir = TempDir::new();
+    let log = dir.write("app.log", "hello");
+    let server = Server::start(&[
+        "--directory",
+        dir.arg(),
+        "--enable-tail",
+        "--tail-max-bytes",
+        "10",
+    ]);
+    let appender = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(300));
+        let mut file = std::fs::OpenOptions::new().append(true).open(log).unwrap();
+        std::io::Write::write_all(&mut file, b" world").unwrap();
+    });
+
+    let response = server.get("/files/app.log?follow=1");
+    appender.join().unwrap();
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("Transfer-Encoding"), Some("chunked"));
+    assert_eq!(response.header("Connection"), Some("close"));
+    assert_eq!(response.text(), "hello worl");
+
+    let response = server.get("/files/app.log?follow=1&x");
+    assert_eq!(response.text(), "hello worl");
+}
+
+#[test]
+fn followed_files_stop_after_the_time_cap() {
+    let dir = TempDir::new();
+    dir.write("app.log", "hello");
+    let server = Server::start(&[
+        "--directory",
+        dir.arg(),
+        "--enable-tail",
+        "--tail-max-secs",
+        "1",
+    ]);
+    assert_eq!(server.get("/files/app.log?follow=1").text(), "hello");
+}
+
+#[test]
+fn following_is_off_by_default() {
+    let dir = TempDir::new();
+    dir.write("app.log", "hello");
+    let server = Server::start(&["--directory", dir.arg()]);
+    let response = server.get("/files/app.log?follow=1");
+    assert_eq!(response.header("Content-Length"), Some("5"));
+    assert_eq!(response.text(), "hello");
+}
+
+#[test]
+fn listing_links_lead_to_the_files() {
+    let dir = TempDir::new();
+    dir.write("a b.txt", "spaced");
+    let server = Server::start(&["--directory", dir.arg(), "--enable-listing"]);
+    let listing = server.get("/files/").text();
+    let link = r#"<a href="/files/a%20b.txt">"#;
+    assert!(listing.contains(link), "{}", listing);
+
+    let response = server.get("/files/a%20b.txt");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.text(), "spaced");
+}
+
+#[test]
+fn listings_cut_long_names_but_link_the_full_one() {
+    let dir = TempDir::new();
+    dir.write("sub/abcdefgh.txt", "");
+    dir.write("sub/a<b>.txt", "");
+    dir.write("sub/nested/x", "");
+    let server = Server::start(&[
+        "--directory",
+        dir.arg(),
+        "--enable-listing",
+        "--listing-name-maxlen",
+        "5",
+    ]);
+    let response = server.get("/files/sub/");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("Content-Type"), Some("text/html"));
+    let listing = response.text();
+    assert!(
+        listing.contains(r#"<a href="/files/sub/abcdefgh.txt">abcd…</a>"#),
+        "{}",
+        listing
+    );
+    assert!(
+        listing.contains(r#"<a href="/files/sub/a%3Cb%3E.txt">a&lt;b&gt;…</a>"#),
+        "{}",
+        listing
+    );
+    assert!(
+        listing.contains(r#"<a href="/files/sub/nested/">nest…/</a>"#),
+        "{}",
+        listing
+    );
+}
+
+fn get_for_host(server: &Server, host: &str, target: &str) -> common::Response {
+    server.send(format!("GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, host).as_bytes())
+}
+
+#[test]
+fn vhosts_serve_their_own_directories() {
+    let a = TempDir::new();
+    a.write("site.txt", "a");
+    let b = TempDir::new();
+    b.write("site.txt", "b");
+    let fallback = TempDir::new();
+    fallback.write("site.txt", "fallback");
+    let server = Server::start(&[
+        "--vhost",
+        &format!("a.test={}", a.arg()),
+        "--vhost",
+        &format!("B.test={}", b.arg()),
+        "--directory",
+        fallback.arg(),
+    ]);
+    assert_eq!(
+        get_for_host(&server, "a.test", "/files/site.txt").text(),
+        "a"
+    );
+    assert_eq!(
+        get_for_host(&server, "b.TEST:8080", "/files/site.txt").text(),
+        "b"
+    );
+    assert_eq!(
+        get_for_host(&server, "c.test", "/files/site.txt").text(),
+        "fallback"
+    );
+}
+
+#[test]
+fn unclaimed_hosts_get_404_or_421() {
+    let a = TempDir::new();
+    a.write("site.txt", "a");
+    let vhost = format!("a.test={}", a.arg());
+
+    let server = Server::start(&["--vhost", &vhost]);
+    assert_eq!(
+        get_for_host(&server, "c.test", "/files/site.txt").status,
+        404
+    );
+    assert_eq!(
+        get_for_host(&server, "a.test", "/files/site.txt").status,
+        200
+    );
+
+    let server = Server::start(&["--vhost", &vhost, "--vhost-no-match", "421"]);
+    assert_eq!(
+        get_for_host(&server, "c.test", "/files/site.txt").status,
+        421
+    );
+    assert_eq!(get_for_host(&server, "c.test", "/").status, 421);
+}
+
+#[test]
+fn files_over_the_size_cap_are_refused() {
+    let dir = TempDir::new();
+    dir.write("big.txt", "0123456789");
+    dir.write("small.txt", "0123");
+    let server = Server::start(&["--directory", dir.arg(), "--max-file-size", "8"]);
+
+    assert_eq!(server.get("/files/big.txt").status, 403);
+    assert_eq!(server.get("/files/small.txt").status, 200);
+
+    let response = server.send(
+        b"POST /files/new.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 9\r\n\r\n012345678",
+    );
+    assert_eq!(response.status, 413);
+    assert_eq!(response.header("Connection"), Some("close"));
+    let response = server.send(
+        b"POST /files/new.txt HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n\
+          9\r\n012345678\r\n0\r\n\r\n",
+    );
+    assert_eq!(response.status, 413);
+    assert!(!dir.path().join("new.txt").exists());
+
+    // PATCH counts what's already there
+    assert_eq!(patch(&server, "small.txt", "4", "4567").status, 204);
+    assert_eq!(patch(&server, "small.txt", "8", "8").status, 413);
+}
+
+#[test]
+fn preload_rules_add_link_headers() {
+    let dir = TempDir::new();
+    dir.write("index.html", "<html></html>");
+    dir.write("notes.txt", "text");
+    let server = Server::start(&[
+        "--directory",
+        dir.arg(),
+        "--preload",
+        "html=/site.css,/app.js",
+    ]);
+    let expected = [
+        "</site.css>; rel=preload; as=style",
+        "</app.js>; rel=preload; as=script",
+    ];
+    assert_eq!(
+        server.get("/files/index.html").headers_named("Link"),
+        expected
+    );
+    let head = server.send(b"HEAD /files/index.html HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(head.headers_named("Link"), expected);
+    assert!(server
+        .get("/files/notes.txt")
+        .headers_named("Link")
+        .is_empty());
+}
+
+#[test]
+fn listings_can_be_json() {
+    let dir = TempDir::new();
+    dir.write("sub/b.txt", "bee");
+    dir.write("sub/a \"q\".txt", "");
+    dir.write("sub/c/inner", "");
+    let server = Server::start(&[
+        "--directory",
+        dir.arg(),
+        "--enable-listing",
+        "--listing-max-entries",
+        "2",
+    ]);
+    let response = server.get("/files/sub/?format=json");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("Content-Type"), Some("application/json"));
+    let listing = response.text();
+    assert!(
+        listing.starts_with(r#"[{"name":"a \"q\".txt","size":0,"is_dir":false,"mtime":"#),
+        "{}",
+        listing
+    );
+    assert!(
+        listing.contains(r#"{"name":"b.txt","size":3,"is_dir":false,"mtime":"#),
+        "{}",
+        listing
+    );
+    // Cut off at --listing-max-entries
+    assert!(!listing.contains(r#""c""#), "{}", listing);
+
+    assert_eq!(
+        server.get("/files/sub/?format=html").header("Content-Type"),
+        Some("text/html")
+    );
+    assert_eq!(server.get("/files/sub/?format=xml").status, 400);
+}
+
+#[test]
+fn the_csp_goes_on_html_responses() {
+    let dir = TempDir::new();
+    dir.write("index.html", "<html></html>");
+    dir.write("notes.txt", "text");
+    let policy = "default-src 'self'";
+
+    let server = Server::start(&["--directory", dir.arg(), "--csp", policy]);
+    let html = server.get("/files/index.html");
+    assert_eq!(html.header("Content-Security-Policy"), Some(policy));
+    assert_eq!(
+        server
+            .get("/files/notes.txt")
+            .header("Content-Security-Policy"),
+        None
+    );
+
+    let server = Server::start(&[
+        "--directory",
+        dir.arg(),
+        "--csp",
+        policy,
+        "--csp-all-responses",
+        "--csp-report-only",
+    ]);
+    let text = server.get("/files/notes.txt");
+    assert_eq!(text.header("Content-Security-Policy"), None);
+    assert_eq!(
+        text.header("Content-Security-Policy-Report-Only"),
+        Some(policy)
+    );
+}
+
+#[test]
+fn an_empty_csp_stops_startup() {
+    let (_, _, stderr) = common::run_to_exit(&["--csp", " ", "--listen", "127.0.0.1:0"]);
+    assert!(stderr.contains("can't be empty"), "{}", stderr);
+}
+
+#[test]
+fn head_on_a_missing_file_matches_get() {
+    let dir = TempDir::new();
+    let server = Server::start(&["--directory", dir.arg()]);
+    let get = server.get("/files/missing");
+    let mut connection = server.connect();
+    connection.write(b"HEAD /files/missing HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    let head = connection.read_response(true);
+    assert_eq!(head.status, 404);
+    assert_eq!(head.header("Content-Length"), Some("0"));
+    assert_eq!(get.header("Content-Length"), Some("0"));
+    let names = |response: &common::Response| -> Vec<String> {
+        response
+            .headers
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect()
+    };
+    assert_eq!(names(&head), names(&get));
+    // Still framed, so the connection carries on with the next response
+    connection.write(b"GET /echo/next HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(connection.read_response(false).text(), "next");
+}
+
+#[test]
+fn cached_files_say_how_old_they_are() {
+    let dir = TempDir::new();
+    dir.write("notes.txt", "text");
+    let server = Server::start(&["--directory", dir.arg(), "--file-cache"]);
+    assert_eq!(server.get("/files/notes.txt").header("Age"), None);
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    let age: u64 = server
+        .get("/files/notes.txt")
+        .header("Age")
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(age >= 1, "{}", age);
+
+    let server = Server::start(&["--directory", dir.arg()]);
+    server.get("/files/notes.txt");
+    assert_eq!(server.get("/files/notes.txt").header("Age"), None);
+}
+
+fn get_encoded(server: &Server, method: &str, target: &str, accept: &str) -> common::Response {
+    server.send(
+        format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: {}\r\n\r\n",
+            method, target, accept
+        )
+        .as_bytes(),
+    )
+}
+
+#[test]
+fn gzip_siblings_go_to_clients_that_accept_them() {
+    let dir = TempDir::new();
+    dir.write("app.js", "plain");
+    dir.write("app.js.gz", "gzipped!");
+    dir.write("other.js", "other");
+    let server = Server::start(&["--directory", dir.arg(), "--precompressed"]);
+
+    let response = get_encoded(&server, "GET", "/files/app.js", "br, gzip;q=0.8");
+    assert_eq!(response.text(), "gzipped!");
+    assert_eq!(response.header("Content-Encoding"), Some("gzip"));
+    assert_eq!(
+        response.header("Content-Location"),
+        Some("/files/app.js.gz")
+    );
+    assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+    // Labelled as what it decodes to
+    assert_eq!(
+        response.header("Content-Type"),
+        Some("application/javascript")
+    );
+
+    let head = get_encoded(&server, "HEAD", "/files/app.js", "gzip");
+    assert_eq!(head.header("Content-Length"), Some("8"));
+    assert_eq!(head.header("Content-Location"), Some("/files/app.js.gz"));
+
+    let response = get_encoded(&server, "GET", "/files/app.js", "gzip;q=0, br");
+    assert_eq!(response.text(), "plain");
+    assert_eq!(response.header("Content-Encoding"), None);
+    assert_eq!(response.header("Content-Location"), None);
+    assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+
+    let response = get_encoded(&server, "GET", "/files/other.js", "gzip");
+    assert_eq!(response.text(), "other");
+    assert_eq!(response.header("Vary"), None);
+}
+
+#[test]
+fn gzip_siblings_are_ignored_by_default() {
+    let dir = TempDir::new();
+    dir.write("app.js", "plain");
+    dir.write("app.js.gz", "gzipped!");
+    let server = Server::start(&["--directory", dir.arg()]);
+    let response = get_encoded(&server, "GET", "/files/app.js", "gzip");
+    assert_eq!(response.text(), "plain");
+    assert_eq!(response.header("Content-Encoding"), None);
+}
@@ -0,0 +1,162 @@
+mod common;
+
+use common::Server;
+use pretty_assertions::assert_eq;
+
+fn echo(server: &Server, framing: &str, body: &str) -> common::Response {
+    server.send(
+        format!(
+            "POST /echo HTTP/1.1\r\nHost: localhost\r\n{}\r\n{}",
+            framing, body
+        )
+        .as_bytes(),
+    )
+}
+
+#[test]
+fn both_framing_headers_are_rejected() {
+    let server = Server::start(&[]);
+    let response = echo(
+        &server,
+        "Transfer-Encoding: chunked\r\nContent-Length: 5\r\n",
+        "5\r\nhello\r\n0\r\n\r\n",
+    );
+    assert_eq!(response.status, 400);
+    assert_eq!(response.header("Connection"), Some("close"));
+}
+
+#[test]
+fn lenient_framing_lets_transfer_encoding_win() {
+    let server = Server::start(&["--lenient-framing"]);
+    let response = echo(
+        &server,
+        "Transfer-Encoding: chunked\r\nContent-Length: 3\r\n",
+        "5\r\nhello\r\n0\r\n\r\n",
+    );
+    assert_eq!(response.status, 200);
+    assert_eq!(response.text(), "hello");
+}
+
+#[test]
+fn chunked_must_be_the_final_coding() {
+    let server = Server::start(&[]);
+    let response = echo(&server, "Transfer-Encoding: chunked, gzip\r\n", "0\r\n\r\n");
+    assert_eq!(response.status, 400);
+}
+
+#[test]
+fn chunked_bodies_are_reassembled() {
+    let server = Server::start(&[]);
+    let response = echo(
+        &server,
+        "Transfer-Encoding: chunked\r\n",
+        "5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\n\r\n",
+    );
+    assert_eq!(response.status, 200);
+    assert_eq!(response.text(), "hello world");
+}
+
+#[test]
+fn malformed_chunk_sizes_get_400() {
+    let server = Server::start(&[]);
+    for size in ["+5", "zz", "", "-1"] {
+        let response = echo(
+            &server,
+            "Transfer-Encoding: chunked\r\n",
+            &format!("{}\r\nhello\r\n0\r\n\r\n", size),
+        );
+        assert_eq!(response.status, 400, "chunk size {:?}", size);
+        assert_eq!(response.header("Connection"), Some("close"));
+    }
+}
+
+#[test]
+fn huge_chunk_sizes_are_refused_without_allocating() {
+    let server = Server::start(&[]);
+    for size in ["fffffffffff", "ffffffffffffffff", "fffffffffffffffffff"] {
+        let response = echo(
+            &server,
+            "Transfer-Encoding: chunked\r\n",
+            &format!("{}\r\n", size),
+        );
+        assert_eq!(response.status, 413, "chunk size {}", size);
+    }
+    // Still up
+    assert_eq!(server.get("/").status, 200);
+}
+
+#[test]
+fn chunk_data_must_end_in_crlf() {
+    let server = Server::start(&[]);
+    let response = echo(
+        &server,
+        "Transfer-Encoding: chunked\r\n",
+        "5\r\nhelloXX0\r\n\r\n",
+    );
+    assert_eq!(response.status, 400);
+}
+
+#[test]
+fn unreadable_content_lengths_are_rejected() {
+    let server = Server::start(&[]);
+    for content_length in ["5x", "+5", "-5", "", "5, 5", "0x5"] {
+        let response = echo(
+            &server,
+            &format!("Content-Length: {}\r\n", content_length),
+            "hello",
+        );
+        assert_eq!(response.status, 400, "Content-Length {:?}", content_length);
+        assert_eq!(response.header("Connection"), Some("close"));
+    }
+}
+
+#[test]
+fn duplicate_content_lengths_must_agree() {
+    let server = Server::start(&[]);
+    let response = echo(&server, "Content-Length: 3\r\nContent-Length: 0\r\n", "hel");
+    assert_eq!(response.status, 400);
+
+    let response = echo(
+        &server,
+        "Content-Length: 5\r\ncontent-length: 5\r\n",
+        "hello",
+    );
+    assert_eq!(response.status, 200);
+    assert_eq!(response.text(), "hello");
+}
+
+#[test]
+fn absurd_content_lengths_are_refused_up_front() {
+    let server = Server::start(&[]);
+    let response = echo(&server, "Content-Length: 17592186044417\r\n", "");
+    assert_eq!(response.status, 413);
+    assert_eq!(server.get("/").status, 200);
+}
+
+fn expect_continue(server: &Server, framing: &str, body: &str) {
+    let mut connection = server.connect();
+    connection.write(
+        format!(
+            "POST /echo HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue\r\n{}\r\n",
+            framing
+        )
+        .as_bytes(),
+    );
+    // The body is only sent once the server asks for it
+    assert_eq!(connection.read_response(false).status, 100);
+    connection.write(body.as_bytes());
+    let response = connection.read_response(false);
+    assert_eq!(response.status, 200);
+    assert_eq!(response.text(), "hello");
+}
+
+#[test]
+fn uploads_are_told_to_continue_however_they_are_framed() {
+    let server = Server::start(&[]);
+    expect_continue(&server, "Content-Length: 5\r\n", "hello");
+    expect_continue(
+        &server,
+        "Transfer-Encoding: chunked\r\n",
+        "5\r\nhello\r\n0\r\n\r\n",
+    );
+}
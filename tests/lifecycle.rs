@@ -0,0 +1,124 @@
+mod common;
+
+use common::{run_to_exit, Server, TempDir};
+use pretty_assertions::assert_eq;
+use std::{fs, net::TcpListener, thread, time::Duration};
+
+#[test]
+fn pid_file_holds_the_pid_until_shutdown() {
+    let dir = TempDir::new();
+    let pid_file = dir.path().join("server.pid");
+    let mut server = Server::start(&["--pid-file", pid_file.to_str().unwrap()]);
+
+    let written = fs::read_to_string(&pid_file).unwrap();
+    assert_eq!(written.trim(), server.pid().to_string());
+
+    server.terminate();
+    server.wait();
+    assert!(!pid_file.exists());
+}
+
+#[test]
+fn requests_in_flight_finish_before_shutdown() {
+    let dir = TempDir::new();
+    let pid_file = dir.path().join("server.pid");
+    let mut server = Server::start(&[
+        "--directory",
+        dir.arg(),
+        "--pid-file",
+        pid_file.to_str().unwrap(),
+    ]);
+    let mut connection = server.connect();
+    connection.write(
+        b"POST /files/upload.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhel",
+    );
+    thread::sleep(Duration::from_millis(200));
+
+    server.terminate();
+    thread::sleep(Duration::from_millis(200));
+    assert!(pid_file.exists());
+    connection.write(b"lo");
+    let response = connection.read_response(false);
+    assert_eq!(response.status, 201);
+    assert_eq!(response.header("Connection"), Some("close"));
+
+    server.wait();
+    assert_eq!(
+        fs::read_to_string(dir.path().join("upload.txt")).unwrap(),
+        "hello"
+    );
+    assert!(!pid_file.exists());
+}
+
+#[test]
+fn requests_on_kept_alive_connections_during_shutdown_get_503() {
+    let mut server = Server::start(&[]);
+    let mut connection = server.connect();
+    connection.write(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(connection.read_response(false).status, 200);
+
+    server.terminate();
+    thread::sleep(Duration::from_millis(200));
+    connection.write(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    let response = connection.read_response(false);
+    assert_eq!(response.status, 503);
+    assert_eq!(response.header("Connection"), Some("close"));
+    assert!(connection.is_closed(Duration::from_secs(1)));
+    server.wait();
+}
+
+#[test]
+fn existing_pid_file_is_only_replaced_when_asked() {
+    let dir = TempDir::new();
+    let pid_file = dir.write("server.pid", "1\n");
+    let pid_arg = pid_file.to_str().unwrap();
+
+    let (status, _, stderr) = run_to_exit(&["--pid-file", pid_arg, "--listen", "127.0.0.1:0"]);
+    assert!(!status.success());
+    assert!(stderr.contains("pid file"), "{}", stderr);
+    assert_eq!(fs::read_to_string(&pid_file).unwrap(), "1\n");
+
+    let server = Server::start(&["--pid-file", pid_arg, "--pid-file-overwrite"]);
+    let written = fs::read_to_string(&pid_file).unwrap();
+    assert_eq!(written.trim(), server.pid().to_string());
+}
+
+#[test]
+fn bind_is_retried_until_the_port_frees_up() {
+    let squatter = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = squatter.local_addr().unwrap().port();
+    let releaser = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(300));
+        drop(squatter);
+    });
+
+    let listen = format!("127.0.0.1:{}", port);
+    let server = Server::start_with(
+        &["--bind-retries", "50", "--bind-retry-delay-ms", "50"],
+        &["--listen", &listen],
+        port,
+    );
+    releaser.join().unwrap();
+    assert_eq!(server.get("/").status, 200);
+    assert!(server
+        .stderr()
+        .iter()
+        .any(|line| line.contains("retry 1/50")));
+}
+
+#[test]
+fn bind_gives_up_after_the_last_retry() {
+    let squatter = TcpListener::bind("127.0.0.1:0").unwrap();
+    let listen = squatter.local_addr().unwrap().to_string();
+    let (status, _, stderr) = run_to_exit(&[
+        "--listen",
+        &listen,
+        "--bind-retries",
+        "2",
+        "--bind-retry-delay-ms",
+        "10",
+    ]);
+    assert!(!status.success());
+    assert!(stderr.contains("retry 2/2"), "{}", stderr);
+    assert!(stderr.contains("error:"), "{}", stderr);
+}
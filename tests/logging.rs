@@ -0,0 +1,67 @@
+mod common;
+
+use common::Server;
+
+#[test]
+fn proxy_headers_are_only_believed_when_trusted() {
+    let request = b"GET /echo/a HTTP/1.1\r\nHost: localhost\r\nForwarded: for=203.0.113.9;proto=https\r\n\r\n";
+
+    let server = Server::start(&[]);
+    server.send(request);
+    let line = server.wait_for_line("GET /echo/a");
+    assert!(line.starts_with("127.0.0.1 http "), "{}", line);
+
+    let server = Server::start(&["--trust-proxy"]);
+    server.send(request);
+    let line = server.wait_for_line("GET /echo/a");
+    assert!(line.starts_with("203.0.113.9 https "), "{}", line);
+}
+
+#[test]
+fn trusted_proxy_headers_cannot_forge_log_lines() {
+    let server = Server::start(&["--trust-proxy"]);
+    server.send(b"GET /echo/xff HTTP/1.1\r\nHost: localhost\r\nX-Forwarded-For: 1.2.3.4 http \"GET /admin HTTP/1.1\" 200\r\n\r\n");
+    let line = server.wait_for_line("GET /echo/xff");
+    assert!(line.starts_with("127.0.0.1 http "), "{}", line);
+    assert!(!line.contains("/admin"), "{}", line);
+
+    server.send(
+        b"GET /echo/fwd HTTP/1.1\r\nHost: localhost\r\nForwarded: for=\"9.9.9.9\rFAKE\"\r\n\r\n",
+    );
+    let line = server.wait_for_line("GET /echo/fwd");
+    assert!(line.starts_with("127.0.0.1 http "), "{}", line);
+    assert!(
+        server.stdout().iter().all(|line| !line.contains("FAKE")),
+        "{:?}",
+        server.stdout()
+    );
+}
+
+const WITH_CREDENTIALS: &[u8] = b"POST /echo HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer s3cret\r\nCookie: session=s3cret\r\nX-Trace: t1\r\nContent-Length: 11\r\n\r\nbody-s3cret";
+
+#[test]
+fn logged_headers_leave_credentials_and_bodies_out() {
+    let server = Server::start(&["--log-headers", "--log-response-headers"]);
+    server.send(WITH_CREDENTIALS);
+    let line = server.wait_for_line("POST /echo");
+    assert!(line.contains("X-Trace: t1"), "{}", line);
+    assert!(line.contains("Authorization: [redacted]"), "{}", line);
+    assert!(line.contains("response_headers="), "{}", line);
+    assert!(
+        server.stdout().iter().all(|line| !line.contains("s3cret")),
+        "{:?}",
+        server.stdout()
+    );
+}
+
+#[test]
+fn the_access_log_can_be_json() {
+    let server = Server::start(&["--log-format", "json", "--log-headers"]);
+    server.send(WITH_CREDENTIALS);
+    let line = server.wait_for_line("POST /echo");
+    assert!(line.starts_with('{') && line.ends_with('}'), "{}", line);
+    assert!(line.contains(r#""client":"127.0.0.1""#), "{}", line);
+    assert!(line.contains(r#""status":"200 OK""#), "{}", line);
+    assert!(line.contains(r#""Cookie":"[redacted]""#), "{}", line);
+    assert!(!line.contains("s3cret"), "{}", line);
+}
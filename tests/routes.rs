@@ -0,0 +1,214 @@
+mod common;
+
+use common::{Server, TempDir};
+use pretty_assertions::assert_eq;
+
+#[test]
+fn echoed_bodies_report_the_original_content_length() {
+    let server = Server::start(&[]);
+    let response =
+        server.send(b"POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhello");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.text(), "hello");
+    assert_eq!(response.header("X-Original-Content-Length"), Some("5"));
+
+    let response = server.send(
+        b"POST /echo HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+    );
+    assert_eq!(response.text(), "hello");
+    assert_eq!(response.header("X-Original-Content-Length"), None);
+}
+
+#[test]
+fn echoes_carry_an_etag_clients_can_revalidate() {
+    let server = Server::start(&[]);
+    let first = server.get("/echo/abc");
+    let etag = first.header("ETag").unwrap().to_string();
+    assert_eq!(etag, server.get("/echo/abc").header("ETag").unwrap());
+    assert_ne!(Some(etag.as_str()), server.get("/echo/abd").header("ETag"));
+
+    let revalidate = |if_none_match: &str| {
+        server.send(
+            format!(
+                "GET /echo/abc HTTP/1.1\r\nHost: localhost\r\nIf-None-Match: {}\r\n\r\n",
+                if_none_match
+            )
+            .as_bytes(),
+        )
+    };
+    let response = revalidate(&etag);
+    assert_eq!(response.status, 304);
+    assert_eq!(response.header("ETag"), Some(etag.as_str()));
+    assert_eq!(response.header("Content-Length"), None);
+    assert_eq!(response.header("Content-Type"), None);
+    assert_eq!(revalidate(&format!("W/{}", etag)).status, 304);
+    assert_eq!(revalidate("\"other\"").status, 200);
+}
+
+#[test]
+fn a_default_favicon_is_served() {
+    let server = Server::start(&[]);
+    let response = server.get("/favicon.ico");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("Content-Type"), Some("image/x-icon"));
+    assert_eq!(
+        response.header("Cache-Control"),
+        Some("public, max-age=604800")
+    );
+    assert_eq!(response.body, include_bytes!("../assets/favicon.ico"));
+}
+
+#[test]
+fn the_served_directory_can_bring_its_own_favicon() {
+    let dir = TempDir::new();
+    dir.write("favicon.ico", "custom");
+    let server = Server::start(&["--directory", dir.arg()]);
+    assert_eq!(server.get("/favicon.ico").text(), "custom");
+}
+
+#[test]
+fn request_count_spans_connections_and_rejections() {
+    let server = Server::start(&["--enable-request-count-header"]);
+    assert_eq!(server.get("/").header("X-Request-Count"), Some("1"));
+    assert_eq!(server.get("/missing").header("X-Request-Count"), Some("2"));
+    let rejected = server.send(b"get / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(rejected.status, 501);
+    assert_eq!(rejected.header("X-Request-Count"), Some("3"));
+}
+
+#[test]
+fn request_count_is_off_by_default() {
+    let server = Server::start(&[]);
+    assert_eq!(server.get("/").header("X-Request-Count"), None);
+}
+
+#[test]
+fn head_is_get_without_the_body() {
+    let server = Server::start(&[]);
+    let get = server.get("/echo/abc");
+    let raw =
+        server.exchange(b"HEAD /echo/abc HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    let raw = String::from_utf8(raw).unwrap();
+    assert!(raw.starts_with("HTTP/1.1 200 OK\r\n"), "{}", raw);
+    assert!(raw.ends_with("\r\n\r\n"), "{:?}", raw);
+    assert!(raw.contains("Content-Length: 3\r\n"), "{}", raw);
+    assert!(
+        raw.contains(&format!("ETag: {}\r\n", get.header("ETag").unwrap())),
+        "{}",
+        raw
+    );
+
+    let favicon = server.send(b"HEAD /favicon.ico HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(favicon.status, 200);
+    assert_eq!(favicon.header("Content-Type"), Some("image/x-icon"));
+}
+
+#[test]
+fn only_allowed_paths_are_served() {
+    let server = Server::start(&["--allow-path", "/echo", "--allow-path", "/health"]);
+    assert_eq!(server.get("/echo/hi").text(), "hi");
+    assert_eq!(server.get("/health").status, 200);
+    assert_eq!(server.get("/").status, 404);
+    assert_eq!(server.get("/echoes").status, 404);
+    assert_eq!(server.get("/user-agent").status, 404);
+}
+
+#[test]
+fn the_user_agent_route_is_matched_exactly() {
+    let server = Server::start(&[]);
+    for (target, status) in [
+        ("/user-agent", 200),
+        ("/x/user-agent", 404),
+        ("/user-agents", 404),
+    ] {
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nUser-Agent: curl\r\n\r\n",
+            target
+        );
+        assert_eq!(server.send(request.as_bytes()).status, status, "{}", target);
+    }
+}
+
+#[test]
+fn bodiless_responses_describe_no_body() {
+    let server = Server::start(&[]);
+    let response = server.send(b"OPTIONS /echo/a HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(response.status, 204);
+    assert_eq!(response.header("Content-Type"), None);
+    assert_eq!(response.header("Content-Length"), None);
+
+    // Other statuses without a body still frame it
+    let response = server.get("/nowhere");
+    assert_eq!(response.status, 404);
+    assert_eq!(response.header("Content-Length"), Some("0"));
+}
+
+#[test]
+fn echoed_bodies_are_byte_exact() {
+    let server = Server::start(&[]);
+    let body = b"caf\xe9 \xff\x00\x80\r\n";
+    let mut request = format!(
+        "POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Type: text/plain; charset=latin-1\r\nContent-Length: {}\r\n\r\n",
+        body.len()
+    )
+    .into_bytes();
+    request.extend_from_slice(body);
+    let response = server.send(&request);
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, body);
+}
+
+#[test]
+fn every_response_names_the_instance() {
+    let server = Server::start(&["--instance-id", "web-7"]);
+    assert_eq!(server.get("/").header("X-Served-By"), Some("web-7"));
+    assert_eq!(server.get("/nowhere").header("X-Served-By"), Some("web-7"));
+    let rejected = server.send(
+        b"GET / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\nContent-Length: 1\r\n\r\n",
+    );
+    assert_eq!(rejected.status, 400);
+    assert_eq!(rejected.header("X-Served-By"), Some("web-7"));
+
+    let server = Server::start(&[]);
+    assert!(server.get("/").header("X-Served-By").is_some());
+}
+
+#[test]
+fn the_headers_file_fills_in_what_routes_leave_unset() {
+    let dir = TempDir::new();
+    let file = dir.write(
+        "headers.txt",
+        "# site-wide\nX-Frame-Options: DENY\n\ncache-control: no-store\n",
+    );
+    let server = Server::start(&["--headers-file", file.to_str().unwrap()]);
+    let response = server.get("/echo/a");
+    assert_eq!(response.header("X-Frame-Options"), Some("DENY"));
+    assert_eq!(response.headers_named("Cache-Control"), ["no-store"]);
+    // The favicon route sets its own caching
+    assert_eq!(
+        server.get("/favicon.ico").headers_named("Cache-Control"),
+        ["public, max-age=604800"]
+    );
+}
+
+#[test]
+fn the_headers_file_cannot_describe_bodies() {
+    let dir = TempDir::new();
+    for line in [
+        "Content-Type: text/html",
+        "Content-Length: 0",
+        "Connection: close",
+        "Bad Name: x",
+        "no colon",
+        "X-Test: a\rInjected: yes",
+    ] {
+        let file = dir.write("headers.txt", line);
+        let (_, _, stderr) = common::run_to_exit(&[
+            "--headers-file",
+            file.to_str().unwrap(),
+            "--listen",
+            "127.0.0.1:0",
+        ]);
+        assert!(stderr.contains("headers.txt:1:"), "{}: {}", line, stderr);
+    }
+}
@@ -0,0 +1,189 @@
+mod common;
+
+use common::Server;
+use pretty_assertions::assert_eq;
+
+#[test]
+fn user_agent_can_be_required() {
+    let server = Server::start(&["--require-user-agent"]);
+    assert_eq!(server.get("/").status, 400);
+
+    let response = server.send(b"GET / HTTP/1.1\r\nHost: localhost\r\nUser-Agent: \r\n\r\n");
+    assert_eq!(response.status, 400);
+
+    let response = server.send(b"GET / HTTP/1.1\r\nHost: localhost\r\nUser-Agent: curl/8\r\n\r\n");
+    assert_eq!(response.status, 200);
+}
+
+#[test]
+fn user_agent_is_optional_by_default() {
+    let server = Server::start(&[]);
+    assert_eq!(server.get("/").status, 200);
+}
+
+#[test]
+fn denied_clients_get_403() {
+    let server = Server::start(&["--deny-ip", "127.0.0.0/8"]);
+    let response = server.get("/");
+    assert_eq!(response.status, 403);
+    assert_eq!(response.header("Connection"), Some("close"));
+}
+
+#[test]
+fn clients_off_the_allowlist_get_403() {
+    let server = Server::start(&["--allow-ip", "10.0.0.0/8"]);
+    assert_eq!(server.get("/").status, 403);
+
+    let server = Server::start(&["--allow-ip", "10.0.0.0/8", "--allow-ip", "127.0.0.1"]);
+    assert_eq!(server.get("/").status, 200);
+}
+
+const FOLDED: &[u8] =
+    b"GET /user-agent HTTP/1.1\r\nHost: localhost\r\nUser-Agent: curl\r\n\t/8.0\r\n\r\n";
+
+#[test]
+fn folded_headers_are_rejected() {
+    let server = Server::start(&[]);
+    assert_eq!(server.send(FOLDED).status, 400);
+}
+
+#[test]
+fn lenient_folding_joins_the_lines() {
+    let server = Server::start(&["--lenient-folding"]);
+    let response = server.send(FOLDED);
+    assert_eq!(response.status, 200);
+    assert_eq!(response.text(), "curl /8.0");
+}
+
+#[test]
+fn long_targets_get_414_query_included() {
+    let server = Server::start(&["--max-uri-length", "12"]);
+    assert_eq!(server.get("/echo/123456").status, 200);
+    assert_eq!(server.get("/echo/1234567").status, 414);
+    assert_eq!(server.get("/echo/1?a=bcd").status, 414);
+}
+
+#[test]
+fn methods_are_case_sensitive() {
+    let server = Server::start(&[]);
+    assert_eq!(
+        server
+            .send(b"get / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .status,
+        501
+    );
+    assert_eq!(
+        server
+            .send(b"BREW / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .status,
+        501
+    );
+}
+
+#[test]
+fn lenient_methods_are_uppercased() {
+    let server = Server::start(&["--lenient-methods"]);
+    let response = server.send(b"get /echo/hi HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.text(), "hi");
+}
+
+#[test]
+fn asterisk_form_is_only_for_options() {
+    let server = Server::start(&[]);
+    let response = server.send(b"OPTIONS * HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(response.status, 204);
+    assert_eq!(
+        response.header("Allow"),
+        Some("OPTIONS, GET, HEAD, POST, PATCH")
+    );
+
+    for method in ["GET", "HEAD", "POST"] {
+        let request = format!(
+            "{} * HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n",
+            method
+        );
+        assert_eq!(server.send(request.as_bytes()).status, 400, "{}", method);
+    }
+}
+
+fn options(server: &Server, target: &str) -> common::Response {
+    server.send(format!("OPTIONS {} HTTP/1.1\r\nHost: localhost\r\n\r\n", target).as_bytes())
+}
+
+#[test]
+fn options_lists_the_methods_of_the_route() {
+    let server = Server::start(&[]);
+    let response = options(&server, "/echo/abc");
+    assert_eq!(response.status, 204);
+    assert_eq!(response.header("Allow"), Some("OPTIONS, GET, HEAD"));
+    assert_eq!(
+        options(&server, "/echo").header("Allow"),
+        Some("OPTIONS, POST")
+    );
+    // Routes that are switched off don't exist
+    assert_eq!(options(&server, "/files/a").status, 404);
+    assert_eq!(options(&server, "/nowhere").status, 404);
+}
+
+#[test]
+fn options_on_unknown_paths_can_answer_server_wide() {
+    let server = Server::start(&["--options-404-on-unknown", "false"]);
+    let response = options(&server, "/nowhere");
+    assert_eq!(response.status, 204);
+    assert_eq!(
+        response.header("Allow"),
+        Some("OPTIONS, GET, HEAD, POST, PATCH")
+    );
+}
+
+#[test]
+fn bodies_on_get_can_be_rejected() {
+    let with_body = b"GET / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 2\r\n\r\nhi";
+    let chunked = b"DELETE /files/a HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+
+    let server = Server::start(&["--reject-get-body"]);
+    let response = server.send(with_body);
+    assert_eq!(response.status, 400);
+    assert_eq!(response.header("Connection"), Some("close"));
+    assert_eq!(server.send(chunked).status, 400);
+    let empty = server.send(b"GET / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n");
+    assert_eq!(empty.status, 200);
+
+    let server = Server::start(&[]);
+    assert_eq!(server.send(with_body).status, 200);
+}
+
+#[test]
+fn control_characters_in_paths_get_400() {
+    let server = Server::start(&[]);
+    for target in [
+        "/echo/a%00b",
+        "/echo/a%0d%0aX-Forged:%20y",
+        "/echo/%C3%28",
+        "/echo/a%7Fb",
+    ] {
+        assert_eq!(server.get(target).status, 400, "{}", target);
+    }
+    let response = server.send(b"GET /echo/a\x01b HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(response.status, 400);
+    // Nor can bytes that were never UTF-8 to begin with
+    let response = server.send(b"GET /echo/a\xffb HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(response.status, 400);
+    // Escapes that aren't two hex digits are left alone, not decoded
+    assert_eq!(server.get("/echo/a%+0b").text(), "a%+0b");
+    assert_eq!(server.get("/echo/caf%C3%A9").status, 200);
+
+    // The raw byte can't break the access log line either
+    let line = server.wait_for_line("/echo/a\\x01b");
+    assert!(line.ends_with(" 400 Bad Request"), "{}", line);
+}
+
+#[test]
+fn latin_1_header_values_are_accepted() {
+    let server = Server::start(&[]);
+    let response =
+        server.send(b"GET /user-agent HTTP/1.1\r\nHost: localhost\r\nUser-Agent: caf\xe9\r\n\r\n");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.text(), "caf\u{e9}");
+}